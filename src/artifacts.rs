@@ -0,0 +1,185 @@
+//! The build manifest `artifacts.json`, written to the staging directory by every build
+//!
+//! Lists the files of the build with their size and SHA-256 hash, the
+//! versions of the kernel and bootloader crates, the git commit of the kernel
+//! crate, the cargo profile, the run command and how long the steps of the
+//! build took, so scripts don't have to guess paths. With
+//! `--message-format json` the manifest is also printed to stdout as a
+//! single line.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use crate::{build::BuildMetadata, Manifest, Manifests};
+
+/// Name of the manifest in the staging directory
+pub const FILE_NAME: &str = "artifacts.json";
+
+/// The duration of each step of a build
+#[derive(Debug, Clone)]
+pub struct Timings {
+    start: Instant,
+    last: Instant,
+    steps: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Records the time since the previous step as `name`
+    pub fn step(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.steps.push((name, now - self.last));
+        self.last = now;
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        let mut timings = json::JsonValue::new_object();
+        for (name, duration) in &self.steps {
+            timings[*name] = (duration.as_millis() as u64).into();
+        }
+        timings["total"] = (self.start.elapsed().as_millis() as u64).into();
+        timings
+    }
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs git in `dir` and returns its trimmed output, `None` if it fails
+pub fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Returns the SHA-256 hash of a file in hex
+pub fn sha256(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(crate::symbol_store::to_hex(&hasher.finalize()))
+}
+
+fn file(path: &Path) -> Result<json::JsonValue> {
+    let size = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .len();
+    let sha256 = sha256(path).with_context(|| format!("Failed to hash {}", path.display()))?;
+    Ok(json::object! {
+        path: path.display().to_string(),
+        size: size,
+        sha256: sha256,
+    })
+}
+
+fn krate(manifest: &Manifest, build_id: Option<&str>) -> json::JsonValue {
+    json::object! {
+        name: manifest.crate_name.as_str(),
+        version: manifest.meta.get_crate_version(),
+        build_id: build_id,
+    }
+}
+
+/// Creates the manifest of a build
+pub fn create(
+    artifacts: &BuildMetadata,
+    manifests: &Manifests,
+    timings: &Timings,
+) -> Result<json::JsonValue> {
+    let mut files = json::JsonValue::new_object();
+    files["iso"] = file(&artifacts.iso_img)?;
+    files["kernel_elf"] = file(&artifacts.kernel_elf)?;
+    files["merged_exe"] = file(&artifacts.merged_exe)?;
+    files["kernel_sym"] = file(&artifacts.kernel_sym)?;
+    if let Some(path) = &artifacts.bootloader_sym {
+        files["bootloader_sym"] = file(path)?;
+    }
+    if let Some(path) = &artifacts.bochs_sym {
+        files["combined_bochsym"] = file(path)?;
+    }
+
+    let placeholders = crate::run::placeholders(artifacts);
+    let run_command = crate::run::run_command(&artifacts.config, artifacts.is_test, false)
+        .iter()
+        .map(|arg| placeholders.substitute_fixed(arg))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let crate_path = &manifests.kernel.crate_path;
+    let commit = git(crate_path, &["rev-parse", "HEAD"]);
+    let is_dirty = commit.is_some()
+        && git(
+            crate_path,
+            &["status", "--porcelain", "--untracked-files=no"],
+        )
+        .is_some_and(|status| !status.is_empty());
+    Ok(json::object! {
+        kernel: krate(&manifests.kernel, artifacts.kernel_build_id.as_deref()),
+        bootloader: manifests
+            .bootloader
+            .as_ref()
+            .map(|bootloader| krate(bootloader, artifacts.bootloader_build_id.as_deref())),
+        git_commit: commit,
+        git_dirty: is_dirty,
+        profile: artifacts.profile.as_str(),
+        is_test: artifacts.is_test,
+        reproducible: artifacts.config.reproducible,
+        files: files,
+        run_command: run_command,
+        timings: timings.to_json(),
+    })
+}
+
+/// Writes the manifest of a build to [`BuildMetadata::artifacts_json`]
+pub fn write(manifest: &json::JsonValue, artifacts: &BuildMetadata) -> Result<()> {
+    let path = &artifacts.artifacts_json;
+    let tmp = crate::staging::temp_path(path);
+    std::fs::write(&tmp, manifest.pretty(2))
+        .and_then(|_| crate::staging::publish(&tmp, path))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_files() {
+        let path = std::env::temp_dir().join(format!("glue_gun-sha256-{}", std::process::id()));
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            sha256(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let entry = file(&path).unwrap();
+        assert_eq!(entry["size"], 3);
+        let _ = std::fs::remove_file(&path);
+
+        let mut timings = Timings::new();
+        timings.step("iso");
+        let timings = timings.to_json();
+        assert!(timings["iso"].is_number());
+        assert!(timings["total"].as_u64().unwrap() >= timings["iso"].as_u64().unwrap());
+    }
+}
@@ -8,46 +8,203 @@ use std::{
 };
 use std::{fs::OpenOptions, io::Write};
 
-use crate::{CliOptions, Manifests};
+use crate::{config::Glue, CliOptions, Manifests};
+
+/// An executable together with what is known about how it was built
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub executable: PathBuf,
+    /// The cargo target kinds, for example `bin` or `test`
+    ///
+    /// Empty for executables given with `--kernel`.
+    pub kind: Vec<String>,
+    /// The cargo profile, derived from the directory cargo placed the executable in
+    pub profile: String,
+    /// Whether the executable is a test harness
+    pub is_test: bool,
+}
+
+impl Artifact {
+    /// Describes an executable that was built outside of glue_gun
+    ///
+    /// Unless `is_test` is given, the directory decides: cargo places test
+    /// harnesses in `deps` and rustdoc builds doctests in `rustdoctest*`.
+    /// Executables elsewhere, for example copied out of the target
+    /// directory, are recognized by the `test_main` function rustc generates
+    /// for test harnesses.
+    pub fn inspect(executable: &Path, is_test: Option<bool>) -> anyhow::Result<Self> {
+        use anyhow::Context;
+        let parent = executable.parent();
+        let is_doctest = parent
+            .and_then(Path::file_name)
+            .and_then(OsStr::to_str)
+            .is_some_and(|dir| dir.starts_with("rustdoctest"));
+        let is_test = match is_test {
+            Some(is_test) => is_test,
+            None if is_doctest || parent.is_some_and(|dir| dir.ends_with("deps")) => true,
+            None => {
+                let data = std::fs::read(executable)
+                    .with_context(|| format!("Failed to read {}", executable.display()))?;
+                has_test_harness(&data).with_context(|| {
+                    format!("Failed to parse kernel ELF {}", executable.display())
+                })?
+            }
+        };
+        Ok(Self {
+            executable: executable.to_path_buf(),
+            kind: Vec::new(),
+            profile: executable
+                .parent()
+                .map(profile_of)
+                .unwrap_or_else(|| "dev".into()),
+            is_test,
+        })
+    }
+
+    /// Parses a `compiler-artifact` message of `cargo build --message-format json`
+    ///
+    /// Returns `None` for other messages and artifacts without executable.
+    fn from_message(message: &json::JsonValue) -> Option<Self> {
+        if message["reason"] != "compiler-artifact" {
+            return None;
+        }
+        let executable = PathBuf::from(message["executable"].as_str()?);
+        Some(Self {
+            kind: message["target"]["kind"]
+                .members()
+                .filter_map(|kind| kind.as_str())
+                .map(str::to_owned)
+                .collect(),
+            profile: executable
+                .parent()
+                .map(profile_of)
+                .unwrap_or_else(|| "dev".into()),
+            is_test: message["profile"]["test"].as_bool().unwrap_or(false),
+            executable,
+        })
+    }
+}
+
+/// Whether the ELF contains the entry point of a test harness
+///
+/// rustc generates `test_main` in the crate root for `custom_test_frameworks`
+/// harnesses and calls `test::test_main_static` from libtest harnesses.
+fn has_test_harness(data: &[u8]) -> anyhow::Result<bool> {
+    use object::{Object, ObjectSymbol};
+    let file = object::File::parse(data)?;
+    Ok(file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .filter_map(|symbol| symbol.name().ok())
+        .any(is_harness_symbol))
+}
+
+/// Whether `name` is `test_main` in a crate root or `test::test_main_static`
+///
+/// Functions named `test_main` in a module, like `kernel::tests::test_main`,
+/// aren't a harness.
+fn is_harness_symbol(name: &str) -> bool {
+    if name == "test_main" {
+        return true;
+    }
+    // Legacy mangling: `_ZN` followed by length-prefixed path components,
+    // the last one the hash `17h<16 hex digits>`
+    let Some(mut rest) = name.strip_prefix("_ZN") else {
+        return false;
+    };
+    let mut path = Vec::new();
+    while let Some(len_end) = rest.find(|c: char| !c.is_ascii_digit()).filter(|&i| i > 0) {
+        let Ok(len) = rest[..len_end].parse::<usize>() else {
+            return false;
+        };
+        let Some(component) = rest.get(len_end..len_end + len) else {
+            return false;
+        };
+        path.push(component);
+        rest = &rest[len_end + len..];
+    }
+    if path.last().is_some_and(|hash| hash.starts_with('h')) {
+        path.pop();
+    }
+    matches!(path[..], [_, "test_main"] | ["test", "test_main_static"])
+}
 
 #[derive(Debug, Clone)]
 pub struct BuildMetadata {
     pub config: crate::config::Config,
     pub is_test: bool,
+    /// The cargo profile the kernel was built with
+    pub profile: String,
     pub iso_img: PathBuf,
+    /// The kernel executable, a copy in the staging directory if glue_gun
+    /// added a build-id
+    pub kernel_elf: PathBuf,
+    pub kernel_sym: PathBuf,
+    /// The executable on the ISO, the bootloader with the kernel or the kernel itself
+    pub merged_exe: PathBuf,
+    /// `None` if the kernel is booted without a bootloader crate
+    pub bootloader_sym: Option<PathBuf>,
+    /// `None` if `bochsym` isn't installed
+    pub bochs_sym: Option<PathBuf>,
+    /// The build manifest, see [`crate::artifacts`]
+    pub artifacts_json: PathBuf,
+    /// The GNU build-id of the kernel in hex, `None` if it couldn't be added
+    pub kernel_build_id: Option<String>,
+    /// The GNU build-id of the merged executable in hex
+    pub bootloader_build_id: Option<String>,
+    /// The directory intermediate files of this kernel are written to
+    pub staging_dir: PathBuf,
+    pub target_dir: PathBuf,
+    pub crate_name: String,
 }
 
 pub fn glue_gun_build(
-    kernel_exec_path: &Path,
+    kernel: &Artifact,
     manifests: &Manifests,
+    config: &crate::config::Config,
     cli_options: &CliOptions,
 ) -> BuildMetadata {
-    // Parse kernel Cargo.toml
-    let config = crate::config::read_config(&manifests.kernel.cargo_toml).unwrap(); // parsed Cargo.toml
-
-    // Find out through directory names if we running a release
-    // or a test version of the binary
-    let target_dir;
-    let is_release;
-    let is_test;
-    {
-        target_dir = kernel_exec_path
-            .parent()
-            .expect("Target executable does not have a parent directory")
-            .to_path_buf();
-        is_release = target_dir.iter().last().unwrap() == OsStr::new("release");
+    let mut timings = crate::artifacts::Timings::new();
+    let kernel_exec_path = kernel.executable.as_path();
+    let config = config.clone();
 
-        let is_doctest = target_dir
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .starts_with("rustdoctest");
-        is_test = is_doctest || target_dir.ends_with("deps");
-    }
-    debug!("Building in release mode? {}", is_release);
+    let target_dir = kernel_exec_path
+        .parent()
+        .expect("Target executable does not have a parent directory")
+        .to_path_buf();
+    let profile = &kernel.profile;
+    let is_test = kernel.is_test;
+    debug!("Kernel built with cargo profile {}", profile);
     debug!("Running a test? {}", is_test);
 
+    // Builds sharing the target directory would overwrite each others files
+    let _lock =
+        crate::staging::BuildLock::acquire(&manifests.kernel.target_dir).unwrap_or_else(|e| {
+            error!(
+                "Failed to lock {}: {}",
+                manifests.kernel.target_dir.display(),
+                e
+            );
+            std::process::exit(1);
+        });
+    let staging_dir = crate::staging::staging_dir(&manifests.kernel.target_dir, kernel_exec_path);
+    std::fs::create_dir_all(&staging_dir).expect("Failed to create staging directory");
+    debug!("Staging directory: {}", staging_dir.display());
+
+    // A kernel embedded into a bootloader crate isn't loaded by GRUB
+    preflight(kernel_exec_path, manifests.bootloader.is_none());
+
+    let symbol_store = crate::symbol_store::location(&config, &manifests.kernel);
+
+    // The cargo artifact is left alone, a kernel without build-id is
+    // continued with as a copy in the staging directory
+    let (kernel_elf, kernel_build_id) = build_id(
+        kernel_exec_path,
+        &staging_dir
+            .join("build-id")
+            .join(kernel_exec_path.file_name().unwrap()),
+    );
+
     // Create kernel.sym file in target directory
     let kernel_sym_path;
     {
@@ -59,81 +216,295 @@ pub fn glue_gun_build(
             .to_owned()
             + ".sym";
         kernel_sym_path = target_dir.join(kernel_sym_name);
-        crate::sym::create_sym_file(kernel_exec_path, &kernel_sym_path, false);
+        crate::sym::create_sym_file(
+            &kernel_elf,
+            &kernel_sym_path,
+            false,
+            config.debug_compression,
+        );
+        archive_symbols(&symbol_store, kernel_build_id.as_deref(), &kernel_sym_path);
     }
+    timings.step("kernel_symbols");
 
-    // Build bootloader crate and set the KERNEL env var
+    // Build bootloader crate and set the `bootloader-kernel-env` env var
     // to the kernel binary.
     // The bootloader binary has in its data section the kernel.
     // So our bootloader binary is now our "kernel"
+    let mut full_kernel_path = manifests.kernel.crate_path.to_owned();
+    full_kernel_path.push(&kernel_elf);
+    if config.strip_kernel {
+        // The kernel executable in the target dir keeps its debug info
+        let stripped_dir = staging_dir.join("stripped");
+        let stripped = stripped_dir.join(kernel_exec_path.file_name().unwrap());
+        // The debuglink names the symbol file, debuggers look for it next to the executable
+        let linked_sym = stripped_dir.join(kernel_sym_path.file_name().unwrap());
+        let result = std::fs::create_dir_all(&stripped_dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| crate::sym::strip_copy(&full_kernel_path, &stripped, &kernel_sym_path))
+            .and_then(|_| Ok(link_or_copy(&kernel_sym_path, &linked_sym)?));
+        match result {
+            Ok(()) => full_kernel_path = stripped,
+            Err(e) => warn!(
+                "Failed to strip {}, keeping its debug info: {:#}",
+                full_kernel_path.display(),
+                e
+            ),
+        }
+    }
     let merged_exe;
-    {
-        let mut full_kernel_path = manifests.kernel.crate_path.to_owned();
-        full_kernel_path.push(kernel_exec_path);
-        let env_vars = [("KERNEL", full_kernel_path.to_str().unwrap())];
-        let features = ["binary"];
+    if let Some(bootloader) = &manifests.bootloader {
+        // With section glue the bootloader doesn't depend on the kernel, so
+        // cargo only builds it once
+        let env_vars = [(
+            config.bootloader_kernel_env.as_str(),
+            full_kernel_path.to_str().unwrap(),
+        )];
+        let env_vars = (config.bootloader_glue == Glue::Env).then_some(&env_vars[..]);
+        let features: Vec<&str> = config
+            .bootloader_features
+            .iter()
+            .map(String::as_str)
+            .collect();
+        let profile = bootloader_profile(
+            &manifests.kernel.cargo_toml,
+            &bootloader.cargo_toml,
+            profile,
+        );
         let exes = cargo_build(
-            &manifests.bootloader.crate_path,
+            &bootloader.crate_path,
             Some(&config),
-            is_release,
+            Some(&profile),
             cli_options.is_very_verbose,
-            Some(&features),
-            Some(&env_vars),
+            (!features.is_empty()).then_some(features.as_slice()),
+            &config.bootloader_cargo_args,
+            env_vars,
         );
 
         if exes.len() != 1 {
             panic!("bootloader generated more then one executable");
         }
 
-        let exe = &exes[0];
-        let dst = exe
-            .parent()
-            .unwrap()
-            .join(kernel_exec_path.file_name().unwrap());
-        std::fs::rename(exe, &dst).expect("Failed to rename bootloader executable");
+        // Copied, the bootloader target dir is shared by all kernels
+        let exe = &exes[0].executable;
+        let dst = staging_dir.join(kernel_exec_path.file_name().unwrap());
+        match config.bootloader_glue {
+            Glue::Env => {
+                std::fs::copy(exe, &dst).expect("Failed to copy bootloader executable");
+            }
+            Glue::Section => {
+                let start = std::time::Instant::now();
+                let bootloader = std::fs::read(exe).expect("Failed to read bootloader executable");
+                let kernel = std::fs::read(&full_kernel_path).expect("Failed to read kernel");
+                let spliced =
+                    crate::splice::splice(&bootloader, &config.bootloader_section, &kernel)
+                        .unwrap_or_else(|e| {
+                            error!("{}: {}", exe.display(), e);
+                            std::process::exit(1);
+                        });
+                std::fs::write(&dst, spliced).expect("Failed to write merged executable");
+                debug!(
+                    "Spliced kernel into {} in {:?}",
+                    config.bootloader_section,
+                    start.elapsed()
+                );
+            }
+        }
 
         merged_exe = dst;
+    } else {
+        // The kernel is multiboot2 compliant on its own
+        merged_exe = full_kernel_path;
     }
     debug!("Merged executable: {:?}", merged_exe);
+    timings.step("bootloader");
+    if manifests.bootloader.is_some() {
+        preflight(&merged_exe, true);
+    }
 
-    // Create bootloader.sym file in target directory
-    let bootloader_sym_path;
-    {
+    // Create bootloader.sym file in the staging directory
+    // The merged executable is glue_gun's own copy
+    let bootloader_build_id = manifests
+        .bootloader
+        .as_ref()
+        .and_then(|_| build_id(&merged_exe, &merged_exe).1);
+    let bootloader_sym_path = manifests.bootloader.as_ref().map(|_| {
         let bootloader_sym_name = "bootloader.sym";
-        bootloader_sym_path = target_dir.join(bootloader_sym_name);
-        crate::sym::create_sym_file(&merged_exe, &bootloader_sym_path, true);
-    }
+        let bootloader_sym_path = staging_dir.join(bootloader_sym_name);
+        crate::sym::create_sym_file(
+            &merged_exe,
+            &bootloader_sym_path,
+            true,
+            config.debug_compression,
+        );
+        archive_symbols(
+            &symbol_store,
+            bootloader_build_id.as_deref(),
+            &bootloader_sym_path,
+        );
+        bootloader_sym_path
+    });
 
     // Create bochs symbolfile if command bochsym available
-    {
+    let bochs_sym_path = {
         let bochs_sym_name = "combined.bochsym";
-        let bochs_sym_path = target_dir.join(bochs_sym_name);
+        let bochs_sym_path = staging_dir.join(bochs_sym_name);
         crate::sym::create_bochs_symfile(
-            [bootloader_sym_path.as_path(), kernel_sym_path.as_path()],
+            bootloader_sym_path
+                .iter()
+                .map(PathBuf::as_path)
+                .chain([kernel_sym_path.as_path()]),
             &bochs_sym_path,
         );
-    }
+        bochs_sym_path.is_file().then_some(bochs_sym_path)
+    };
+    timings.step("symbols");
 
-    // Create an ISO image from our merged exe
+    // Create an ISO image from our merged exe, a running emulator keeps
+    // reading the previous image until the new one replaces it
     let iso_img;
     let iso_dir;
     {
         let kernel_name = merged_exe.file_stem().unwrap().to_str().unwrap();
         iso_img = target_dir.join(format!("{}.iso", kernel_name));
-        iso_dir = target_dir.join("isofiles");
-        info!("Created Iso image at: {}", iso_img.to_str().unwrap());
+        iso_dir = staging_dir.join("isofiles");
 
-        glue_grub(&iso_dir, &iso_img, &merged_exe);
+        let epoch = config.reproducible.then(|| {
+            crate::reproducible::source_date_epoch().unwrap_or_else(|e| {
+                error!("{:#}", e);
+                std::process::exit(1);
+            })
+        });
+        let tmp_img = crate::staging::temp_path(&iso_img);
+        glue_grub(&iso_dir, &tmp_img, &merged_exe, epoch);
+        crate::staging::publish(&tmp_img, &iso_img).unwrap_or_else(|e| {
+            panic!("Failed to publish {}: {}", iso_img.display(), e);
+        });
+        info!("Created Iso image at: {}", iso_img.to_str().unwrap());
     }
+    timings.step("iso");
 
-    BuildMetadata {
+    let artifacts = BuildMetadata {
         config,
         iso_img,
         is_test,
+        profile: profile.clone(),
+        kernel_elf,
+        kernel_sym: kernel_sym_path,
+        merged_exe,
+        bootloader_sym: bootloader_sym_path,
+        bochs_sym: bochs_sym_path,
+        artifacts_json: staging_dir.join(crate::artifacts::FILE_NAME),
+        kernel_build_id: kernel_build_id.as_deref().map(crate::symbol_store::to_hex),
+        bootloader_build_id: bootloader_build_id
+            .as_deref()
+            .map(crate::symbol_store::to_hex),
+        staging_dir,
+        target_dir: manifests.kernel.target_dir.clone(),
+        crate_name: manifests.kernel.crate_name.clone(),
+    };
+
+    let bootloader_exe = manifests
+        .bootloader
+        .as_ref()
+        .map(|_| artifacts.merged_exe.as_path());
+    match crate::size::glue_gun_size(&artifacts, bootloader_exe, cli_options.size_report) {
+        Ok(problems) if problems.is_empty() => {}
+        Ok(problems) => {
+            for problem in problems {
+                error!("{}", problem);
+            }
+            error!("The build exceeds `size-budget`");
+            std::process::exit(1);
+        }
+        Err(e) => warn!("Failed to create the size report: {:#}", e),
+    }
+
+    let manifest = crate::artifacts::create(&artifacts, manifests, &timings).and_then(|manifest| {
+        crate::artifacts::write(&manifest, &artifacts)?;
+        Ok(manifest)
+    });
+    match manifest {
+        Ok(manifest) => {
+            debug!("Wrote {}", artifacts.artifacts_json.display());
+            if cli_options.json_messages {
+                println!("{}", manifest.dump());
+            }
+        }
+        Err(e) => warn!("Failed to create the build manifest: {:#}", e),
+    }
+    artifacts
+}
+
+/// Exits with the problems found if `exe` can't be booted, see [`crate::preflight`]
+fn preflight(exe: &Path, multiboot2: bool) {
+    let data = std::fs::read(exe).unwrap_or_else(|e| {
+        error!("Failed to read {}: {}", exe.display(), e);
+        std::process::exit(1);
+    });
+    let problems = crate::preflight::check(&data, multiboot2);
+    if problems.is_empty() {
+        debug!("Preflight checks of {} passed", exe.display());
+        return;
     }
+    for problem in &problems {
+        error!("{}: {}", exe.display(), problem);
+    }
+    std::process::exit(1);
 }
 
-pub fn glue_grub(iso_dir: &PathBuf, iso_img: &PathBuf, executable: &PathBuf) {
+/// Returns the build-id of `exe` and the executable to continue with, a
+/// copy at `out_path` if it had no build-id, failures only warn
+fn build_id(exe: &Path, out_path: &Path) -> (PathBuf, Option<Vec<u8>>) {
+    match crate::sym::ensure_build_id(exe, out_path) {
+        Ok((path, id)) => {
+            info!(
+                "Build-id of {}: {}",
+                exe.file_name().unwrap().to_str().unwrap(),
+                crate::symbol_store::to_hex(&id)
+            );
+            (path, Some(id))
+        }
+        Err(e) => {
+            warn!("Failed to add a build-id to {}: {:#}", exe.display(), e);
+            (exe.to_path_buf(), None)
+        }
+    }
+}
+
+/// Hard links `src` to `dst`, copies it if they are on different file systems
+fn link_or_copy(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let _ = std::fs::remove_file(dst);
+    std::fs::hard_link(src, dst).or_else(|_| std::fs::copy(src, dst).map(drop))
+}
+
+/// Archives a symbol file in the symbol store, failures only warn
+fn archive_symbols(store: &Path, build_id: Option<&[u8]>, sym: &Path) {
+    let Some(build_id) = build_id else {
+        return;
+    };
+    if let Err(e) = crate::symbol_store::archive(store, build_id, sym) {
+        warn!(
+            "Failed to archive {} in {}: {}",
+            sym.display(),
+            store.display(),
+            e
+        );
+    }
+}
+
+/// Creates a GRUB rescue image booting `executable` with multiboot2
+///
+/// With an `epoch` the image is reproducible, see [`crate::reproducible`].
+pub fn glue_grub(iso_dir: &PathBuf, iso_img: &PathBuf, executable: &PathBuf, epoch: Option<u64>) {
+    if epoch.is_some() {
+        // Files left over from earlier builds would end up on the image
+        match std::fs::remove_dir_all(iso_dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                panic!("{} Failed to clear iso dir {}", e, iso_dir.display())
+            }
+            _ => (),
+        }
+    }
     match std::fs::create_dir(iso_dir) {
         Ok(_) => (),
         Err(e) => {
@@ -167,7 +538,7 @@ pub fn glue_grub(iso_dir: &PathBuf, iso_img: &PathBuf, executable: &PathBuf) {
         .create(true)
         .write(true)
         .truncate(true)
-        .open(&grub_dir.join("grub.cfg"))
+        .open(grub_dir.join("grub.cfg"))
         .unwrap();
 
     grubcfg
@@ -190,6 +561,16 @@ pub fn glue_grub(iso_dir: &PathBuf, iso_img: &PathBuf, executable: &PathBuf) {
     let mut cmd = process::Command::new("grub-mkrescue");
     cmd.arg("-o").arg(iso_img);
     cmd.arg(iso_dir);
+    if let Some(epoch) = epoch {
+        crate::reproducible::normalize_tree(iso_dir, epoch).unwrap_or_else(|e| {
+            panic!("{} Failed to normalize iso dir {}", e, iso_dir.display());
+        });
+        crate::reproducible::grub_mkrescue_args(&mut cmd, epoch);
+        debug!(
+            "Reproducible image dated {}",
+            crate::reproducible::mkisofs_date(epoch)
+        );
+    }
 
     let output = cmd.output().expect("Failed to build bootloader crate");
     if !output.status.success() {
@@ -200,14 +581,90 @@ pub fn glue_grub(iso_dir: &PathBuf, iso_img: &PathBuf, executable: &PathBuf) {
     }
 }
 
+/// Returns the cargo profile of an executable in `target_dir`
+///
+/// Cargo places the artifacts of the `dev` and `test` profiles in `debug`,
+/// every other profile in a directory named after it.
+fn profile_of(target_dir: &Path) -> String {
+    let dir = if target_dir.ends_with("deps") {
+        target_dir.parent().unwrap_or(target_dir)
+    } else {
+        target_dir
+    };
+    match dir.file_name().and_then(OsStr::to_str) {
+        Some("debug") | None => "dev".into(),
+        Some(name) if name.starts_with("rustdoctest") => "dev".into(),
+        Some(name) => name.into(),
+    }
+}
+
+/// Returns the profile the bootloader crate is built with for a kernel built with `profile`
+///
+/// Custom profiles only exist in the manifest defining them. If the
+/// bootloader doesn't define the kernel's profile, it is built with the
+/// built-in profile the kernel's profile inherits from.
+fn bootloader_profile(kernel_toml: &Path, bootloader_toml: &Path, profile: &str) -> String {
+    const BUILTIN: [&str; 4] = ["dev", "release", "test", "bench"];
+    let profiles = |manifest: &Path| {
+        std::fs::read_to_string(manifest)
+            .ok()
+            .and_then(|content| content.parse::<toml::Value>().ok())
+            .and_then(|manifest| manifest.get("profile").cloned())
+    };
+    if BUILTIN.contains(&profile)
+        || profiles(bootloader_toml).is_some_and(|profiles| profiles.get(profile).is_some())
+    {
+        return profile.into();
+    }
+
+    let kernel_profiles = profiles(kernel_toml);
+    let mut current = profile.to_string();
+    // Bounded, so cyclic `inherits` can't loop forever
+    for _ in 0..16 {
+        if BUILTIN.contains(&current.as_str()) {
+            debug!(
+                "Bootloader doesn't define profile {}, using {}",
+                profile, current
+            );
+            return current;
+        }
+        match kernel_profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(&current))
+            .and_then(|profile| profile.get("inherits"))
+            .and_then(|inherits| inherits.as_str())
+        {
+            Some(inherits) => current = inherits.into(),
+            None => break,
+        }
+    }
+    warn!(
+        "Couldn't resolve the built-in profile of cargo profile {}, building the bootloader with dev",
+        profile
+    );
+    "dev".into()
+}
+
+/// Whether `args` already select a cargo profile
+fn selects_profile(args: &[String]) -> bool {
+    args.iter().any(|arg| {
+        arg == "--release" || arg == "-r" || arg == "--profile" || arg.starts_with("--profile=")
+    })
+}
+
+/// Builds `target_crate` and returns the built executables with their metadata
+///
+/// `profile` is passed as `--profile` unless `args` select one themselves.
+/// `args` are appended to the cargo command line unchanged.
 pub fn cargo_build(
     target_crate: &Path,
     config: Option<&crate::config::Config>,
-    is_release: bool,
+    profile: Option<&str>,
     is_verbose: bool,
     features: Option<&[&str]>,
+    args: &[String],
     env: Option<&[(&str, &str)]>,
-) -> Vec<PathBuf> {
+) -> Vec<Artifact> {
     info!(
         "Building crate {}",
         target_crate.file_name().unwrap().to_str().unwrap()
@@ -238,13 +695,21 @@ pub fn cargo_build(
         ));
     }
 
-    if is_release {
-        cmd.arg("--release");
+    match profile {
+        _ if selects_profile(args) => {}
+        None | Some("dev") => {}
+        Some("release") => {
+            cmd.arg("--release");
+        }
+        Some(profile) => {
+            cmd.arg("--profile").arg(profile);
+        }
     }
 
     if is_verbose {
         cmd.arg("-vv");
     }
+    cmd.args(args);
     cmd.arg("--message-format").arg("json");
 
     cmd.stdout(process::Stdio::piped());
@@ -259,10 +724,133 @@ pub fn cargo_build(
         );
     }
     for line in String::from_utf8(output.stdout).unwrap().lines() {
-        let mut artifact = json::parse(line).expect("Failed parsing json from cargo");
-        if let Some(executable) = artifact["executable"].take_string() {
-            executables.push(PathBuf::from(executable));
+        let message = json::parse(line).expect("Failed parsing json from cargo");
+        if let Some(artifact) = Artifact::from_message(&message) {
+            debug!("Built artifact: {:?}", artifact);
+            executables.push(artifact);
         }
     }
     executables
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_tests() {
+        // The unit tests are a libtest harness themselves
+        let exe = std::env::current_exe().unwrap();
+        assert!(Artifact::inspect(&exe, None).unwrap().is_test);
+        assert!(!Artifact::inspect(&exe, Some(false)).unwrap().is_test);
+
+        let message = json::parse(
+            r#"{
+                "reason": "compiler-artifact",
+                "target": {"kind": ["bin"], "name": "kernel", "test": true},
+                "profile": {"opt_level": "3", "debug_assertions": false, "test": true},
+                "executable": "/tmp/target/x86_64-none/ci/deps/kernel-0123abcd"
+            }"#,
+        )
+        .unwrap();
+        let artifact = Artifact::from_message(&message).unwrap();
+        assert_eq!(artifact.kind, ["bin"]);
+        assert_eq!(artifact.profile, "ci");
+        assert!(artifact.is_test);
+        let message = json::parse(r#"{"reason": "build-finished", "success": true}"#).unwrap();
+        assert!(Artifact::from_message(&message).is_none());
+    }
+
+    /// Writes an ELF defining `symbols` to `path`
+    fn write_elf(path: &Path, symbols: &[&str]) {
+        use object::write::{Object, Symbol, SymbolSection};
+        let mut elf = Object::new(
+            object::BinaryFormat::Elf,
+            object::Architecture::X86_64,
+            object::Endianness::Little,
+        );
+        let text = elf.section_id(object::write::StandardSection::Text);
+        elf.append_section_data(text, &[0xc3], 1);
+        for name in symbols {
+            elf.add_symbol(Symbol {
+                name: name.as_bytes().to_vec(),
+                value: 0,
+                size: 1,
+                kind: object::SymbolKind::Text,
+                scope: object::SymbolScope::Linkage,
+                weak: false,
+                section: SymbolSection::Section(text),
+                flags: object::SymbolFlags::None,
+            });
+        }
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, elf.write().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn detects_tests_by_directory() {
+        let dir = std::env::temp_dir().join(format!("glue_gun-inspect-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // `test_main` was inlined, the directory still says it's a test
+        let inlined = dir.join("debug/deps/kernel-0123abcd");
+        write_elf(&inlined, &["_start"]);
+        let artifact = Artifact::inspect(&inlined, None).unwrap();
+        assert!(artifact.is_test);
+        assert_eq!(artifact.profile, "dev");
+
+        // A binary with a `test_main` of its own isn't a harness
+        let renamed = dir.join("release/kernel");
+        write_elf(
+            &renamed,
+            &["_start", "_ZN6kernel5tests9test_main17h0123456789abcdefE"],
+        );
+        assert!(!Artifact::inspect(&renamed, None).unwrap().is_test);
+
+        // Outside `deps` the harness is recognized by its symbols
+        let copied = dir.join("kernel-test");
+        write_elf(&copied, &["_ZN6kernel9test_main17h0123456789abcdefE"]);
+        assert!(Artifact::inspect(&copied, None).unwrap().is_test);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(is_harness_symbol("test_main"));
+        assert!(is_harness_symbol(
+            "_ZN4test16test_main_static17h0123456789abcdefE"
+        ));
+        assert!(!is_harness_symbol("_ZN4test9test_main17h"));
+        assert!(!is_harness_symbol("my_test_main"));
+    }
+
+    #[test]
+    fn resolves_profiles() {
+        let target = Path::new("target/x86_64-none");
+        assert_eq!(profile_of(&target.join("debug")), "dev");
+        assert_eq!(profile_of(&target.join("release/deps")), "release");
+        assert_eq!(profile_of(&target.join("ci-fast")), "ci-fast");
+
+        let dir = std::env::temp_dir().join(format!("glue_gun-profiles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kernel = dir.join("kernel.toml");
+        let bootloader = dir.join("bootloader.toml");
+        std::fs::write(
+            &kernel,
+            "[profile.ci]\ninherits = \"release\"\n\
+             [profile.ci-fast]\ninherits = \"ci\"\n\
+             [profile.shared]\ninherits = \"dev\"\n",
+        )
+        .unwrap();
+        std::fs::write(&bootloader, "[profile.shared]\ninherits = \"release\"\n").unwrap();
+
+        assert_eq!(
+            bootloader_profile(&kernel, &bootloader, "ci-fast"),
+            "release"
+        );
+        assert_eq!(bootloader_profile(&kernel, &bootloader, "shared"), "shared");
+        assert_eq!(bootloader_profile(&kernel, &bootloader, "test"), "test");
+        assert_eq!(bootloader_profile(&kernel, &bootloader, "unknown"), "dev");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(selects_profile(&["--profile=ci".into()]));
+        assert!(!selects_profile(&["--locked".into()]));
+    }
+}
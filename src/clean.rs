@@ -5,6 +5,21 @@ use std::{env, path::Path, process};
 use crate::CliOptions;
 
 pub fn glue_gun_clean(manifests: &crate::Manifests, cli_options: CliOptions, clean_all: bool) {
+    // Remove the staging directories, before cargo may remove the target directory
+    let kernel = &manifests.kernel;
+    let crate_name = (!clean_all).then_some(kernel.crate_name.as_str());
+    match crate::staging::remove_staging_dirs(&kernel.target_dir, crate_name) {
+        Ok(removed) => debug!("Removed {} staging directories", removed),
+        Err(e) => {
+            error!(
+                "Failed to remove the staging directories in {}: {}",
+                kernel.target_dir.join("glue_gun").display(),
+                e
+            );
+            process::exit(1);
+        }
+    }
+
     // Clean kernel crate
     let kernel_crate_name: Option<Vec<String>> =
         (!clean_all).then(|| vec![manifests.kernel.crate_name.clone()]);
@@ -18,16 +33,17 @@ pub fn glue_gun_clean(manifests: &crate::Manifests, cli_options: CliOptions, cle
     );
 
     // Clean bootloader crate
-    let bootloader_crate_names =
-        (!clean_all).then(|| vec![manifests.bootloader.crate_name.clone()]);
+    if let Some(bootloader) = &manifests.bootloader {
+        let bootloader_crate_names = (!clean_all).then(|| vec![bootloader.crate_name.clone()]);
 
-    cargo_clean(
-        &manifests.bootloader.crate_path,
-        bootloader_crate_names,
-        cli_options.is_release,
-        cli_options.is_very_verbose,
-        None,
-    );
+        cargo_clean(
+            &bootloader.crate_path,
+            bootloader_crate_names,
+            cli_options.is_release,
+            cli_options.is_very_verbose,
+            None,
+        );
+    }
 }
 
 pub fn cargo_clean(
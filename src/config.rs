@@ -1,8 +1,17 @@
-//! Parses the `package.metadata.glue_gun` configuration table
+//! Reads the glue_gun configuration from the Cargo manifests, configuration
+//! files, environment variables and the command line
 
+use crate::qemu::QemuConfig;
 use anyhow::{anyhow, Context, Result};
-use std::path::Path;
-use toml::Value;
+use log::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+use toml::{value::Table, Value};
 
 /// Represents the `package.metadata.glue_gun` configuration table
 ///
@@ -16,14 +25,22 @@ pub struct Config {
     ///
     /// Defaults to `build`.
     pub build_command: Vec<String>,
+    /// Additional arguments passed to cargo when building the kernel
+    pub kernel_cargo_args: Vec<String>,
+    /// Additional arguments passed to cargo when building the bootloader crate
+    pub bootloader_cargo_args: Vec<String>,
     /// The run command that is invoked on `glue_gun run --debug`
     ///
     /// The substring "{}" will be replaced with the path to the bootable disk image.
+    /// Assembled from the `qemu` table unless `debug-run-command` is set.
     pub debug_run_command: Vec<String>,
     /// The run command that is invoked on `glue_gun run`
     ///
     /// The substring "{}" will be replaced with the path to the bootable disk image.
+    /// Assembled from the `qemu` table unless `run-command` is set.
     pub run_command: Vec<String>,
+    /// The parsed `package.metadata.glue_gun.qemu` table
+    pub qemu: QemuConfig,
     /// Additional arguments passed to the runner for not-test binaries
     ///
     /// Applies to `glue_gun run` and `glue_gun run`.
@@ -33,83 +50,1247 @@ pub struct Config {
     /// Applies to `glue_gun run`.
     pub test_args: Option<Vec<String>>,
     /// The timeout for running an test through `glue_gun test` or `glue_gun runner` in seconds
+    ///
+    /// At least 1.
     pub test_timeout: u32,
     /// An exit code that should be considered as success for test executables (applies to
     /// `glue_gun runner`)
     pub test_success_exit_code: Option<i32>,
+    /// Whether test executables are run with `-no-reboot`, so a triple fault
+    /// ends the test instead of rebooting the kernel
+    ///
+    /// Defaults to `true`. With `false` glue_gun doesn't add the flag, a
+    /// `-no-reboot` in `run-command` or `test-args` is still passed.
+    pub test_no_reboot: bool,
+    /// Whether addresses in the serial output should be resolved against the
+    /// kernel and bootloader symbol files
+    ///
+    /// Defaults to `true`.
+    pub symbolize_backtraces: bool,
+    /// The regex used to find addresses in the serial output
+    ///
+    /// The capture group named `addr` (or the first capture group) has to
+    /// contain the address in hex, with or without `0x` prefix.
+    pub backtrace_regex: String,
+    /// Whether QEMU should log interrupts and CPU resets and print a crash
+    /// summary after an abnormal exit or a test timeout
+    ///
+    /// Defaults to `false`. Can be enabled with `glue_gun run --diagnose`.
+    pub diagnostics: bool,
+    /// The items passed to QEMU's `-d` flag in diagnostic mode
+    ///
+    /// Defaults to `int,cpu_reset`.
+    pub diagnostics_log_items: String,
+    /// The file QEMU logs to in diagnostic mode
+    ///
+    /// Defaults to `<image>.qemu.log` next to the disk image.
+    pub diagnostics_log: Option<PathBuf>,
+    /// Whether QEMU is started with a QMP socket, see [`crate::qmp::socket_path`]
+    ///
+    /// Used for a graceful shutdown, a screenshot and a register dump when a
+    /// test times out. Defaults to `true`.
+    pub qmp: bool,
+    /// Whether a screenshot of the emulator display is saved as PNG next to
+    /// the disk image when a test fails or times out
+    ///
+    /// Requires `qmp`. Defaults to `true`.
+    pub test_screenshots: bool,
+    /// The interval in milliseconds in which the display of a running test is
+    /// captured, the last capture is kept if the test fails
+    ///
+    /// Defaults to `500`.
+    pub test_screenshot_interval: u32,
+    /// The QEMU display backend for non-test runs
+    ///
+    /// Defaults to QEMU's own choice.
+    pub display: Option<Display>,
+    /// The QEMU display backend for test runs
+    ///
+    /// Defaults to `none`.
+    pub test_display: Display,
+    /// Whether the kernel is embedded into a bootloader crate or booted directly
+    ///
+    /// Defaults to `crate`.
+    pub bootloader: Bootloader,
+    /// The name of the kernel dependency that is the bootloader crate
+    ///
+    /// Defaults to `bootloader`. Ignored if `bootloader-path` is set.
+    pub bootloader_crate: String,
+    /// The directory of the bootloader crate, relative to the kernel crate
+    pub bootloader_path: Option<PathBuf>,
+    /// The features enabled when building the bootloader crate
+    ///
+    /// Defaults to `["binary"]`.
+    pub bootloader_features: Vec<String>,
+    /// The environment variable the path to the kernel executable is passed
+    /// to the bootloader build in
+    ///
+    /// Defaults to `KERNEL`.
+    pub bootloader_kernel_env: String,
+    /// How the kernel is embedded into the bootloader crate
+    ///
+    /// Defaults to `env`.
+    pub bootloader_glue: Glue,
+    /// The placeholder section the kernel is spliced into with `bootloader-glue = "section"`
+    ///
+    /// Defaults to `.kernel`.
+    pub bootloader_section: String,
+    /// The directory symbol files are archived in by build-id, relative to the kernel crate
+    ///
+    /// Defaults to `<target-dir>/glue_gun/symbols`.
+    pub symbol_store: Option<PathBuf>,
+    /// How the debug sections of `kernel.sym` and `bootloader.sym` are compressed
+    ///
+    /// Defaults to `none`.
+    pub debug_compression: Compression,
+    /// Whether the kernel is stripped of its debug info before it is put on the ISO
+    ///
+    /// Defaults to `false`.
+    pub strip_kernel: bool,
+    /// Whether a size report is printed after every build
+    ///
+    /// Defaults to `false`.
+    pub size_report: bool,
+    /// Whether the ISO is bit-identical for the same kernel, with its dates
+    /// taken from `SOURCE_DATE_EPOCH`
+    ///
+    /// Defaults to `false`.
+    pub reproducible: bool,
+    /// The name of the `glue_gun dist` archive, with `{crate_name}`,
+    /// `{version}`, `{git_describe}` and `{profile}` placeholders
+    ///
+    /// Defaults to `{crate_name}-{version}-{git_describe}`.
+    pub dist_name: String,
+    /// The archive format of `glue_gun dist`
+    ///
+    /// Defaults to `tar.zst`.
+    pub dist_format: DistFormat,
+    /// The maximum size in bytes of the `iso` and of sections of the kernel
+    /// and the bootloader executable, keyed `kernel.<section>` and
+    /// `bootloader.<section>` with section names without the leading dot
+    pub size_budget: BTreeMap<String, u64>,
+    /// Where each configured key was set, keys without an entry use the default
+    pub sources: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Returns the resolved configuration as `package.metadata.glue_gun` table
+    pub fn to_table(&self) -> Table {
+        fn strings(values: &[String]) -> Value {
+            Value::Array(values.iter().cloned().map(Value::String).collect())
+        }
+        let mut table = Table::new();
+        let mut set = |key: &str, value: Value| {
+            table.insert(key.to_string(), value);
+        };
+        set("build-command", strings(&self.build_command));
+        set("kernel-cargo-args", strings(&self.kernel_cargo_args));
+        set(
+            "bootloader-cargo-args",
+            strings(&self.bootloader_cargo_args),
+        );
+        set("run-command", strings(&self.run_command));
+        set("debug-run-command", strings(&self.debug_run_command));
+        if let Some(run_args) = &self.run_args {
+            set("run-args", strings(run_args));
+        }
+        if let Some(test_args) = &self.test_args {
+            set("test-args", strings(test_args));
+        }
+        set("test-timeout", Value::Integer(self.test_timeout.into()));
+        if let Some(exit_code) = self.test_success_exit_code {
+            set("test-success-exit-code", Value::Integer(exit_code.into()));
+        }
+        set("test-no-reboot", Value::Boolean(self.test_no_reboot));
+        set(
+            "symbolize-backtraces",
+            Value::Boolean(self.symbolize_backtraces),
+        );
+        set(
+            "backtrace-regex",
+            Value::String(self.backtrace_regex.clone()),
+        );
+        set("diagnostics", Value::Boolean(self.diagnostics));
+        set(
+            "diagnostics-log-items",
+            Value::String(self.diagnostics_log_items.clone()),
+        );
+        if let Some(log) = &self.diagnostics_log {
+            set("diagnostics-log", Value::String(log.display().to_string()));
+        }
+        set("qmp", Value::Boolean(self.qmp));
+        set("test-screenshots", Value::Boolean(self.test_screenshots));
+        set(
+            "test-screenshot-interval",
+            Value::Integer(self.test_screenshot_interval.into()),
+        );
+        if let Some(display) = self.display {
+            set("display", Value::String(display.to_string()));
+        }
+        set("test-display", Value::String(self.test_display.to_string()));
+        set("bootloader", Value::String(self.bootloader.to_string()));
+        set(
+            "bootloader-crate",
+            Value::String(self.bootloader_crate.clone()),
+        );
+        if let Some(path) = &self.bootloader_path {
+            set("bootloader-path", Value::String(path.display().to_string()));
+        }
+        set("bootloader-features", strings(&self.bootloader_features));
+        set(
+            "bootloader-kernel-env",
+            Value::String(self.bootloader_kernel_env.clone()),
+        );
+        set(
+            "bootloader-glue",
+            Value::String(self.bootloader_glue.to_string()),
+        );
+        set(
+            "bootloader-section",
+            Value::String(self.bootloader_section.clone()),
+        );
+        if let Some(path) = &self.symbol_store {
+            set("symbol-store", Value::String(path.display().to_string()));
+        }
+        set(
+            "debug-compression",
+            Value::String(self.debug_compression.to_string()),
+        );
+        set("strip-kernel", Value::Boolean(self.strip_kernel));
+        set("size-report", Value::Boolean(self.size_report));
+        set("reproducible", Value::Boolean(self.reproducible));
+        set("dist-name", Value::String(self.dist_name.clone()));
+        set("dist-format", Value::String(self.dist_format.to_string()));
+        if !self.size_budget.is_empty() {
+            let mut budget = Table::new();
+            for (key, size) in &self.size_budget {
+                let size = Value::Integer(*size as i64);
+                match key.split_once('.') {
+                    Some((binary, section)) => {
+                        if let Value::Table(sections) = budget
+                            .entry(binary.to_string())
+                            .or_insert_with(|| Value::Table(Table::new()))
+                        {
+                            sections.insert(section.to_string(), size);
+                        }
+                    }
+                    None => {
+                        budget.insert(key.clone(), size);
+                    }
+                }
+            }
+            set("size-budget", Value::Table(budget));
+        }
+        set("qemu", Value::Table(self.qemu.to_table()));
+        table
+    }
+
+    /// Renders every resolved key as `key = value  # source`
+    ///
+    /// Implements `glue_gun config --show`.
+    pub fn show(&self) -> String {
+        // Nested tables, like `size-budget.kernel`, are flattened as well
+        fn flatten(key: String, value: Value, entries: &mut Vec<(String, Value)>) {
+            match value {
+                Value::Table(table) => {
+                    for (sub_key, value) in table {
+                        flatten(format!("{}.{}", key, sub_key), value, entries);
+                    }
+                }
+                value => entries.push((key, value)),
+            }
+        }
+        let mut entries = Vec::new();
+        for (key, value) in self.to_table() {
+            flatten(key, value, &mut entries);
+        }
+        let lines: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| (format!("{} = {}", key, value), self.source(&key)))
+            .collect();
+        let width = lines.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+        lines
+            .into_iter()
+            .map(|(line, source)| format!("{:width$}  # {}\n", line, source, width = width))
+            .collect()
+    }
+
+    fn source(&self, key: &str) -> &str {
+        match self.sources.get(key) {
+            Some(source) => source,
+            None if key == "run-command" || key == "debug-run-command" => "qemu table",
+            None => "default",
+        }
+    }
+}
+
+/// A QEMU display backend selectable through `display` or `--display`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Display {
+    None,
+    Gtk,
+    Sdl,
+    /// A VNC server on a free localhost port
+    Vnc,
+    Curses,
+}
+
+impl Display {
+    pub const VARIANTS: [&'static str; 5] = ["none", "gtk", "sdl", "vnc", "curses"];
+}
+
+impl FromStr for Display {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Display::None),
+            "gtk" => Ok(Display::Gtk),
+            "sdl" => Ok(Display::Sdl),
+            "vnc" => Ok(Display::Vnc),
+            "curses" => Ok(Display::Curses),
+            _ => Err(anyhow!(
+                "unknown display `{}`, expected one of {}",
+                s,
+                Display::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Display::None => "none",
+            Display::Gtk => "gtk",
+            Display::Sdl => "sdl",
+            Display::Vnc => "vnc",
+            Display::Curses => "curses",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How glue_gun turns the kernel executable into the image GRUB boots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bootloader {
+    /// The kernel is embedded into the bootloader crate, which is booted
+    Crate,
+    /// The kernel is multiboot2 compliant and booted as is
+    None,
+}
+
+impl Bootloader {
+    pub const VARIANTS: [&'static str; 2] = ["crate", "none"];
+}
+
+impl FromStr for Bootloader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "crate" => Ok(Bootloader::Crate),
+            "none" => Ok(Bootloader::None),
+            _ => Err(anyhow!(
+                "unknown bootloader `{}`, expected one of {}",
+                s,
+                Bootloader::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Bootloader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Bootloader::Crate => "crate",
+            Bootloader::None => "none",
+        })
+    }
+}
+
+/// How the kernel is embedded into the bootloader crate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Glue {
+    /// The bootloader is rebuilt with the path of the kernel in `bootloader-kernel-env`
+    Env,
+    /// The bootloader is built once, the kernel is spliced into `bootloader-section`
+    Section,
+}
+
+impl Glue {
+    pub const VARIANTS: [&'static str; 2] = ["env", "section"];
+}
+
+impl FromStr for Glue {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "env" => Ok(Glue::Env),
+            "section" => Ok(Glue::Section),
+            _ => Err(anyhow!(
+                "unknown bootloader glue `{}`, expected one of {}",
+                s,
+                Glue::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Glue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Glue::Env => "env",
+            Glue::Section => "section",
+        })
+    }
+}
+
+/// How the debug sections of symbol files are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zlib,
+    Zstd,
+}
+
+impl Compression {
+    pub const VARIANTS: [&'static str; 3] = ["none", "zlib", "zstd"];
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zlib" => Ok(Compression::Zlib),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(anyhow!(
+                "unknown debug compression `{}`, expected one of {}",
+                s,
+                Compression::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Zlib => "zlib",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// The archive format of `glue_gun dist`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistFormat {
+    /// A zstd compressed tarball
+    TarZst,
+    Zip,
+}
+
+impl DistFormat {
+    pub const VARIANTS: [&'static str; 2] = ["tar.zst", "zip"];
+}
+
+impl FromStr for DistFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tar.zst" => Ok(DistFormat::TarZst),
+            "zip" => Ok(DistFormat::Zip),
+            _ => Err(anyhow!(
+                "unknown dist format `{}`, expected one of {}",
+                s,
+                DistFormat::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DistFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DistFormat::TarZst => "tar.zst",
+            DistFormat::Zip => "zip",
+        })
+    }
+}
+
+/// Selects the profile and the command line overrides used when reading the configuration
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOptions {
+    /// The profile applied on top of the base configuration
+    pub profile: Option<String>,
+    /// `key=value` pairs passed through `--config`
+    pub overrides: Vec<String>,
+}
+
+/// Reads the layered glue_gun configuration of the kernel with the given Cargo.toml
+///
+/// Each layer overrides the keys of the previous ones:
+///
+/// 1. built-in defaults
+/// 2. `workspace.metadata.glue_gun` of the enclosing workspace
+/// 3. `package.metadata.glue_gun` of the kernel
+/// 4. `glue_gun.toml` next to the kernel Cargo.toml
+/// 5. `~/.config/glue_gun/config.toml`
+/// 6. the selected profile of any of the files above
+/// 7. `GLUE_GUN_*` environment variables
+/// 8. `--config key=value` arguments
+pub fn read_config(manifest_path: &Path, options: &ConfigOptions) -> Result<Config> {
+    read_config_inner(manifest_path, options).context("Failed to read glue_gun configuration")
+}
+
+/// Reads all profiles together with their resolved configuration
+pub fn read_profiles(
+    manifest_path: &Path,
+    options: &ConfigOptions,
+) -> Result<BTreeMap<String, Config>> {
+    profile_names(&read_files(manifest_path)?)?
+        .into_iter()
+        .map(|name| {
+            let options = ConfigOptions {
+                profile: Some(name.clone()),
+                ..options.clone()
+            };
+            let config = read_config(manifest_path, &options)?;
+            Ok((name, config))
+        })
+        .collect()
+}
+
+/// A configuration table together with a description of where it was read from
+#[derive(Debug, Clone)]
+struct Layer {
+    source: String,
+    table: Table,
+    /// Whether the table may contain a `profiles` table
+    has_profiles: bool,
+    /// The file the table was read from, used to point diagnostics at a line
+    file: Option<SourceFile>,
+}
+
+/// A TOML file and the path of a configuration table within it
+#[derive(Debug, Clone)]
+struct SourceFile {
+    path: PathBuf,
+    text: String,
+    table_path: Vec<String>,
+}
+
+impl Layer {
+    fn file(source: String, table: Table, file: SourceFile) -> Self {
+        Self {
+            source,
+            table,
+            has_profiles: true,
+            file: Some(file),
+        }
+    }
+
+    /// A layer setting a single, possibly dotted, key like `qemu.memory`
+    fn single(source: String, key: &str, value: Value) -> Self {
+        let mut table = Table::new();
+        let mut parts = key.rsplit('.');
+        table.insert(parts.next().unwrap().to_string(), value);
+        for part in parts {
+            let mut outer = Table::new();
+            outer.insert(part.to_string(), Value::Table(table));
+            table = outer;
+        }
+        Self {
+            source,
+            table,
+            has_profiles: false,
+            file: None,
+        }
+    }
+
+    /// Creates a diagnostic for the key at `path` within the layer's table
+    fn diagnostic(&self, path: &[&str], error: anyhow::Error) -> Diagnostic {
+        let location = match &self.file {
+            Some(file) => {
+                let full_path: Vec<&str> = file
+                    .table_path
+                    .iter()
+                    .map(String::as_str)
+                    .chain(path.iter().copied())
+                    .collect();
+                match locate(&file.text, &full_path) {
+                    Some((line, column)) => {
+                        format!("{}:{}:{}", file.path.display(), line, column)
+                    }
+                    None => format!("{} ({})", file.path.display(), full_path.join(".")),
+                }
+            }
+            None => self.source.clone(),
+        };
+        Diagnostic {
+            location,
+            message: format!("{:#}", error),
+        }
+    }
+}
+
+/// Finds the line and column of the key at `path` in a TOML document
+///
+/// Understands `[table]` headers, dotted keys and single line inline tables,
+/// which covers the ways glue_gun tables are usually written.
+fn locate(text: &str, path: &[&str]) -> Option<(usize, usize)> {
+    let (key, tables) = path.split_last()?;
+    let split = |keys: &str| -> Vec<String> {
+        keys.split('.')
+            .map(|part| part.trim().trim_matches('"').to_string())
+            .collect()
+    };
+    let mut header = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if let Some(name) = trimmed.strip_prefix('[') {
+            header = split(name.trim_start_matches('[').split(']').next().unwrap_or(""));
+            continue;
+        }
+        let Some((keys, value)) = trimmed.split_once('=') else {
+            continue;
+        };
+        let mut full_path = header.clone();
+        full_path.extend(split(keys));
+        if full_path == path {
+            return Some((index + 1, line.len() - trimmed.len() + 1));
+        }
+        if full_path == tables && value.trim_start().starts_with('{') {
+            let offset = line.len() - value.len();
+            if let Some(column) = value.find(key) {
+                return Some((index + 1, offset + column + 1));
+            }
+        }
+    }
+    None
+}
+
+/// A problem found in the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// `file:line:column`, or the environment variable or argument that set the key
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// The configuration contains invalid keys or values
+#[derive(Debug, Error)]
+pub struct ConfigErrors(pub Vec<Diagnostic>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.as_slice() {
+            [diagnostic] => write!(f, "{}", diagnostic),
+            diagnostics => {
+                write!(f, "{} problems found", diagnostics.len())?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
-/// Reads the configuration from a `package.metadata.glue_gun` in the given Cargo.toml.
-pub fn read_config(manifest_path: &Path) -> Result<Config> {
-    read_config_inner(manifest_path).context("Failed to read glue_gun configuration")
+fn read_toml(path: &Path) -> Result<(String, Value)> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let value = text
+        .parse::<Value>()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok((text, value))
 }
 
-fn read_config_inner(manifest_path: &Path) -> Result<Config> {
-    use std::{fs::File, io::Read};
-    let cargo_toml: Value = {
-        let mut content = String::new();
-        File::open(manifest_path)
-            .context("Failed to open Cargo.toml")?
-            .read_to_string(&mut content)
-            .context("Failed to read Cargo.toml")?;
-        content
-            .parse::<Value>()
-            .context("Failed to parse Cargo.toml")?
+/// Returns the table at `path` within `value`, if it exists
+fn get_table(value: &Value, path: &[&str]) -> Result<Option<Table>> {
+    let mut current = value;
+    for key in path {
+        match current.get(key) {
+            Some(next) => current = next,
+            None => return Ok(None),
+        }
+    }
+    current
+        .as_table()
+        .cloned()
+        .map(Some)
+        .ok_or_else(|| anyhow!("`{}` must be a table", path.join(".")))
+}
+
+/// Reads the table at `table_path` of the TOML file at `path` as a layer
+fn read_file_layer(path: &Path, table_path: &[&str], source: String) -> Result<Option<Layer>> {
+    let (text, value) = read_toml(path)?;
+    let table = match get_table(&value, table_path)? {
+        Some(table) => table,
+        None => return Ok(None),
+    };
+    let file = SourceFile {
+        path: path.to_path_buf(),
+        text,
+        table_path: table_path.iter().map(|s| s.to_string()).collect(),
     };
+    Ok(Some(Layer::file(source, table, file)))
+}
 
-    let metadata = cargo_toml
-        .get("package")
-        .and_then(|table| table.get("metadata"))
-        .and_then(|table| table.get("glue_gun"));
-    let metadata = match metadata {
-        None => {
-            log::warn!("Couldn't find package.metadata.glue_gun attribute using defaults...");
-            return Ok(ConfigBuilder::default().into());
-        }
-        Some(metadata) => metadata
-            .as_table()
-            .ok_or_else(|| anyhow!("glue_gun configuration invalid: {:?}", metadata))?,
+/// Finds the closest Cargo.toml with a `workspace` table, starting at the kernel itself
+fn find_workspace_manifest(manifest_path: &Path) -> Result<Option<PathBuf>> {
+    let crate_dir = manifest_path.parent().unwrap_or_else(|| Path::new("."));
+    for dir in crate_dir.ancestors() {
+        let cargo_toml = dir.join("Cargo.toml");
+        if cargo_toml.is_file() && read_toml(&cargo_toml)?.1.get("workspace").is_some() {
+            return Ok(Some(cargo_toml));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the path of the per-user configuration file
+fn user_config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("glue_gun").join("config.toml"))
+}
+
+/// Reads the configuration files in order of increasing precedence
+fn read_files(manifest_path: &Path) -> Result<Vec<Layer>> {
+    let mut files = Vec::new();
+    if let Some(workspace) = find_workspace_manifest(manifest_path)? {
+        let source = format!("workspace.metadata.glue_gun in {}", workspace.display());
+        files.extend(read_file_layer(
+            &workspace,
+            &["workspace", "metadata", "glue_gun"],
+            source,
+        )?);
+    }
+    match read_file_layer(
+        manifest_path,
+        &["package", "metadata", "glue_gun"],
+        "package.metadata.glue_gun".into(),
+    )? {
+        Some(layer) => files.push(layer),
+        None => debug!(
+            "No package.metadata.glue_gun table in {}",
+            manifest_path.display()
+        ),
+    }
+    let local = manifest_path.with_file_name("glue_gun.toml");
+    let user = user_config_path();
+    for path in std::iter::once(local).chain(user) {
+        if path.is_file() {
+            files.extend(read_file_layer(&path, &[], path.display().to_string())?);
+        }
+    }
+    Ok(files)
+}
+
+/// Turns the `GLUE_GUN_*` environment variables into layers
+///
+/// `GLUE_GUN_TEST_TIMEOUT` sets `test-timeout`, a double underscore selects a
+/// sub table: `GLUE_GUN_QEMU__MEMORY` sets `qemu.memory`. `GLUE_GUN_PROFILE`
+/// selects the profile and is not a configuration key. Variables that don't
+/// name a configuration key are ignored with a warning, they may belong to
+/// something else.
+fn env_layers() -> Vec<Layer> {
+    env_layers_from(std::env::vars())
+}
+
+fn env_layers_from(vars: impl IntoIterator<Item = (String, String)>) -> Vec<Layer> {
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(name, _)| name.starts_with("GLUE_GUN_") && name != "GLUE_GUN_PROFILE")
+        .collect();
+    vars.sort();
+    vars.into_iter()
+        .filter_map(|(name, value)| {
+            let key = name["GLUE_GUN_".len()..]
+                .to_lowercase()
+                .replace("__", ".")
+                .replace('_', "-");
+            if !is_env_key(&key) {
+                log::warn!(
+                    "Ignoring environment variable {}, `{}` is not a configuration key",
+                    name,
+                    key
+                );
+                return None;
+            }
+            Some(Layer::single(
+                format!("environment variable {}", name),
+                &key,
+                parse_override(&value),
+            ))
+        })
+        .collect()
+}
+
+/// Whether an environment variable can set `key`
+fn is_env_key(key: &str) -> bool {
+    let is_table = |name: &str| {
+        KEYS.iter()
+            .any(|(key, kind)| *key == name && *kind == "table")
     };
+    match key.split_once('.') {
+        None => KEYS
+            .iter()
+            .any(|(name, kind)| *name == key && *kind != "table"),
+        Some(("qemu", sub_key)) => crate::qemu::KEYS.contains(&sub_key),
+        Some(("profiles", _)) => false,
+        Some((table, sub_key)) => !sub_key.is_empty() && is_table(table),
+    }
+}
+
+/// Turns `--config key=value` arguments into layers
+fn override_layers(overrides: &[String]) -> Result<Vec<Layer>> {
+    overrides
+        .iter()
+        .map(|arg| {
+            let (key, value) = arg
+                .split_once('=')
+                .ok_or_else(|| anyhow!("`--config {}` must have the form key=value", arg))?;
+            Ok(Layer::single(
+                format!("--config {}", arg),
+                key.trim(),
+                parse_override(value.trim()),
+            ))
+        })
+        .collect()
+}
+
+/// Parses an override as TOML value, falling back to a plain string
+///
+/// `60`, `true` and `["-m", "1G"]` keep their type, `kvm` becomes a string.
+fn parse_override(value: &str) -> Value {
+    format!("value = {}", value)
+        .parse::<Value>()
+        .ok()
+        .and_then(|table| table.get("value").cloned())
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+fn read_config_inner(manifest_path: &Path, options: &ConfigOptions) -> Result<Config> {
+    let files = read_files(manifest_path)?;
+    if files.is_empty() {
+        log::warn!("Couldn't find package.metadata.glue_gun attribute using defaults...");
+    }
+
+    let mut layers = files.clone();
+    if let Some(profile) = &options.profile {
+        let mut found = false;
+        for file in &files {
+            let table = match file.table.get("profiles").and_then(|p| p.get(profile)) {
+                Some(Value::Table(table)) => table.clone(),
+                Some(_) => return Err(anyhow!("profile `{}` must be a table", profile)),
+                None => continue,
+            };
+            found = true;
+            let file_info = file.file.clone().map(|mut info| {
+                info.table_path
+                    .extend(["profiles".to_string(), profile.clone()]);
+                info
+            });
+            layers.push(Layer {
+                source: format!("{} profile `{}`", file.source, profile),
+                table,
+                has_profiles: false,
+                file: file_info,
+            });
+        }
+        if !found {
+            return Err(anyhow!(
+                "profile `{}` not found, available profiles: [{}]",
+                profile,
+                profile_names(&files).unwrap_or_default().join(", ")
+            ));
+        }
+    }
+    layers.extend(env_layers());
+    layers.extend(override_layers(&options.overrides)?);
 
     let mut config = ConfigBuilder::default();
+    let mut diagnostics = Vec::new();
+    for layer in &layers {
+        config.apply(layer, &mut diagnostics);
+    }
+    let config: Config = config.into();
+    diagnostics.extend(validate_placeholders(&config));
+    if !diagnostics.is_empty() {
+        return Err(ConfigErrors(diagnostics).into());
+    }
+    Ok(config)
+}
+
+/// Rejects unknown placeholders in the run commands
+fn validate_placeholders(config: &Config) -> Vec<Diagnostic> {
+    let commands = [
+        ("run-command", Some(&config.run_command)),
+        ("debug-run-command", Some(&config.debug_run_command)),
+        ("run-args", config.run_args.as_ref()),
+        ("test-args", config.test_args.as_ref()),
+    ];
+    let mut diagnostics = Vec::new();
+    for (key, args) in commands {
+        for arg in args.into_iter().flatten() {
+            if let Err(e) = crate::placeholder::validate(arg) {
+                diagnostics.push(Diagnostic {
+                    location: format!("`{}` from {}", key, config.source(key)),
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Returns the names of the profiles defined in any of the configuration files
+fn profile_names(files: &[Layer]) -> Result<Vec<String>> {
+    let mut names = BTreeSet::new();
+    for file in files {
+        match file.table.get("profiles") {
+            None => {}
+            Some(Value::Table(profiles)) => names.extend(profiles.keys().cloned()),
+            Some(_) => {
+                return Err(anyhow!(
+                    "`profiles` in {} must be a table of profile tables",
+                    file.source
+                ))
+            }
+        }
+    }
+    Ok(names.into_iter().collect())
+}
+
+/// The keys of the configuration table together with the expected type
+const KEYS: [(&str, &str); 37] = [
+    ("build-command", "list of strings"),
+    ("kernel-cargo-args", "list of strings"),
+    ("bootloader-cargo-args", "list of strings"),
+    ("run-command", "list of strings"),
+    ("debug-run-command", "list of strings"),
+    ("run-args", "list of strings"),
+    ("test-args", "list of strings"),
+    ("test-timeout", "integer"),
+    ("test-success-exit-code", "integer"),
+    ("test-no-reboot", "boolean"),
+    ("symbolize-backtraces", "boolean"),
+    ("backtrace-regex", "string"),
+    ("diagnostics", "boolean"),
+    ("diagnostics-log-items", "string"),
+    ("diagnostics-log", "string"),
+    ("qmp", "boolean"),
+    ("test-screenshots", "boolean"),
+    ("test-screenshot-interval", "integer"),
+    ("display", "string"),
+    ("test-display", "string"),
+    ("bootloader", "string"),
+    ("bootloader-crate", "string"),
+    ("bootloader-path", "string"),
+    ("bootloader-features", "list of strings"),
+    ("bootloader-kernel-env", "string"),
+    ("bootloader-glue", "string"),
+    ("bootloader-section", "string"),
+    ("symbol-store", "string"),
+    ("debug-compression", "string"),
+    ("strip-kernel", "boolean"),
+    ("size-report", "boolean"),
+    ("reproducible", "boolean"),
+    ("dist-name", "string"),
+    ("dist-format", "string"),
+    ("size-budget", "table"),
+    ("qemu", "table"),
+    ("profiles", "table"),
+];
+
+/// Returns the candidate closest to the misspelled `key`, if any is close enough
+pub(crate) fn suggest<'a>(
+    key: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .map(|candidate| (strsim::jaro_winkler(key, candidate), candidate))
+        .filter(|(similarity, _)| *similarity > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| candidate)
+}
+
+/// Formats the message for an unknown key, with a suggestion for typos
+pub(crate) fn unknown_key<'a>(
+    key: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> anyhow::Error {
+    match suggest(key, candidates) {
+        Some(suggestion) => anyhow!("unknown key `{}`, did you mean `{}`?", key, suggestion),
+        None => anyhow!("unknown key `{}`", key),
+    }
+}
+
+/// Converts an integer key into the target type, rejecting values out of range
+fn in_range<T>(key: &str, value: i64, min: T, max: T) -> Result<T>
+where
+    T: TryFrom<i64> + Into<i64> + fmt::Display + Copy,
+{
+    match T::try_from(value) {
+        Ok(converted) if (min.into()..=max.into()).contains(&value) => Ok(converted),
+        _ => Err(anyhow!(
+            "`{}` must be between {} and {}, got {}",
+            key,
+            min,
+            max,
+            value
+        )),
+    }
+}
 
-    for (key, value) in metadata {
-        match (key.as_str(), value.clone()) {
-            ("test-timeout", Value::Integer(timeout)) if timeout.is_negative() => {
-                return Err(anyhow!("test-timeout must not be negative"))
+impl ConfigBuilder {
+    /// Applies the keys of a configuration layer, overriding earlier values
+    ///
+    /// Invalid keys are skipped and reported in `diagnostics`, so all
+    /// problems of all layers can be shown at once.
+    fn apply(&mut self, layer: &Layer, diagnostics: &mut Vec<Diagnostic>) {
+        for (key, value) in &layer.table {
+            match (key.as_str(), value) {
+                ("profiles", Value::Table(_)) if layer.has_profiles => {}
+                ("profiles", _) if !layer.has_profiles => diagnostics.push(layer.diagnostic(
+                    &[key],
+                    anyhow!("profiles can't be nested or set from the command line"),
+                )),
+                ("qemu", Value::Table(table)) => {
+                    let qemu = self.qemu.get_or_insert_with(QemuConfig::default);
+                    for (qemu_key, value) in table {
+                        match qemu.apply_key(qemu_key, value) {
+                            Ok(()) => {
+                                self.sources
+                                    .insert(format!("qemu.{}", qemu_key), layer.source.clone());
+                            }
+                            Err(e) => diagnostics.push(layer.diagnostic(&[key, qemu_key], e)),
+                        }
+                    }
+                }
+                ("size-budget", Value::Table(table)) => {
+                    let mut entries = Vec::new();
+                    for (name, value) in table {
+                        match (name.as_str(), value) {
+                            ("iso", value) => entries.push((vec![name.as_str()], value)),
+                            ("kernel" | "bootloader", Value::Table(sections)) => entries.extend(
+                                sections
+                                    .iter()
+                                    .map(|(section, value)| (vec![name.as_str(), section], value)),
+                            ),
+                            ("kernel" | "bootloader", _) => diagnostics.push(layer.diagnostic(
+                                &[key, name],
+                                anyhow!(
+                                    "expected a table of section budgets, like \
+                                     `{} = {{ text = \"512K\" }}`",
+                                    name
+                                ),
+                            )),
+                            _ => diagnostics.push(layer.diagnostic(
+                                &[key, name],
+                                anyhow!(
+                                    "unknown size budget `{}`, expected `iso`, \
+                                     `kernel.<section>` or `bootloader.<section>`",
+                                    name
+                                ),
+                            )),
+                        }
+                    }
+                    for (path, value) in entries {
+                        let budget_key = path.join(".");
+                        match parse_size(value) {
+                            Ok(size) => {
+                                self.size_budget.insert(budget_key.clone(), size);
+                                self.sources.insert(
+                                    format!("size-budget.{}", budget_key),
+                                    layer.source.clone(),
+                                );
+                            }
+                            Err(e) => diagnostics
+                                .push(layer.diagnostic(&[&[key.as_str()][..], &path].concat(), e)),
+                        }
+                    }
+                }
+                (key, value) => match self.apply_key(key, value.clone()) {
+                    Ok(()) => {
+                        self.sources.insert(key.to_string(), layer.source.clone());
+                    }
+                    Err(e) => diagnostics.push(layer.diagnostic(&[key], e)),
+                },
             }
+        }
+    }
+
+    fn apply_key(&mut self, key: &str, value: Value) -> Result<()> {
+        let config = self;
+        match (key, value) {
             ("test-timeout", Value::Integer(timeout)) => {
-                config.test_timeout = Some(timeout as u32);
+                config.test_timeout = Some(in_range(key, timeout, 1, u32::MAX)?);
             }
             ("test-success-exit-code", Value::Integer(exit_code)) => {
-                config.test_success_exit_code = Some(exit_code as i32);
+                // Process exit codes are truncated to 8 bits
+                config.test_success_exit_code = Some(in_range(key, exit_code, 0, 255)?);
+            }
+            ("test-no-reboot", Value::Boolean(no_reboot)) => {
+                config.test_no_reboot = Some(no_reboot);
             }
             ("build-command", Value::Array(array)) => {
-                config.build_command = Some(parse_string_array(array, "build-command")?);
+                config.build_command = Some(parse_string_array(array, key)?);
+            }
+            ("kernel-cargo-args", Value::Array(array)) => {
+                config.kernel_cargo_args = Some(parse_string_array(array, key)?);
+            }
+            ("bootloader-cargo-args", Value::Array(array)) => {
+                config.bootloader_cargo_args = Some(parse_string_array(array, key)?);
             }
             ("run-command", Value::Array(array)) => {
-                config.run_command = Some(parse_string_array(array, "run-command")?);
+                config.run_command = Some(parse_string_array(array, key)?);
             }
             ("debug-run-command", Value::Array(array)) => {
-                config.debug_run_command = Some(parse_string_array(array, "debug-run-command")?);
+                config.debug_run_command = Some(parse_string_array(array, key)?);
             }
             ("run-args", Value::Array(array)) => {
-                config.run_args = Some(parse_string_array(array, "run-args")?);
+                config.run_args = Some(parse_string_array(array, key)?);
             }
             ("test-args", Value::Array(array)) => {
-                config.test_args = Some(parse_string_array(array, "test-args")?);
+                config.test_args = Some(parse_string_array(array, key)?);
+            }
+            ("symbolize-backtraces", Value::Boolean(symbolize)) => {
+                config.symbolize_backtraces = Some(symbolize);
+            }
+            ("backtrace-regex", Value::String(regex)) => {
+                regex::Regex::new(&regex).context("`backtrace-regex` is not a valid regex")?;
+                config.backtrace_regex = Some(regex);
+            }
+            ("diagnostics", Value::Boolean(diagnostics)) => {
+                config.diagnostics = Some(diagnostics);
+            }
+            ("diagnostics-log-items", Value::String(items)) => {
+                config.diagnostics_log_items = Some(items);
+            }
+            ("diagnostics-log", Value::String(path)) => {
+                config.diagnostics_log = Some(PathBuf::from(path));
+            }
+            ("qmp", Value::Boolean(qmp)) => {
+                config.qmp = Some(qmp);
+            }
+            ("display", Value::String(display)) => {
+                config.display = Some(display.parse().context("invalid `display`")?);
+            }
+            ("test-display", Value::String(display)) => {
+                config.test_display = Some(display.parse().context("invalid `test-display`")?);
+            }
+            ("test-screenshots", Value::Boolean(screenshots)) => {
+                config.test_screenshots = Some(screenshots);
+            }
+            ("test-screenshot-interval", Value::Integer(interval)) => {
+                config.test_screenshot_interval = Some(in_range(key, interval, 1, u32::MAX)?);
+            }
+            ("bootloader", Value::String(bootloader)) => {
+                config.bootloader = Some(bootloader.parse()?);
+            }
+            ("bootloader-crate", Value::String(name)) => {
+                config.bootloader_crate = Some(name);
+            }
+            ("bootloader-path", Value::String(path)) => {
+                config.bootloader_path = Some(PathBuf::from(path));
+            }
+            ("bootloader-features", Value::Array(array)) => {
+                config.bootloader_features = Some(parse_string_array(array, key)?);
+            }
+            ("bootloader-kernel-env", Value::String(var)) => {
+                if var.is_empty() || var.contains(['=', '\0']) {
+                    return Err(anyhow!(
+                        "`{}` must be a valid environment variable name, got `{}`",
+                        key,
+                        var
+                    ));
+                }
+                config.bootloader_kernel_env = Some(var);
+            }
+            ("bootloader-glue", Value::String(glue)) => {
+                config.bootloader_glue = Some(glue.parse()?);
+            }
+            ("bootloader-section", Value::String(section)) => {
+                config.bootloader_section = Some(section);
+            }
+            ("symbol-store", Value::String(path)) => {
+                config.symbol_store = Some(PathBuf::from(path));
+            }
+            ("debug-compression", Value::String(compression)) => {
+                config.debug_compression = Some(compression.parse()?);
+            }
+            ("strip-kernel", Value::Boolean(strip)) => {
+                config.strip_kernel = Some(strip);
+            }
+            ("size-report", Value::Boolean(report)) => {
+                config.size_report = Some(report);
+            }
+            ("reproducible", Value::Boolean(reproducible)) => {
+                config.reproducible = Some(reproducible);
+            }
+            ("dist-name", Value::String(name)) => {
+                config.dist_name = Some(name);
+            }
+            ("dist-format", Value::String(format)) => {
+                config.dist_format = Some(format.parse()?);
             }
             (key, value) => {
-                return Err(anyhow!(
-                    "unexpected `package.metadata.glue_gun` \
-                 key `{}` with value `{}`",
-                    key,
-                    value
-                ))
+                return match KEYS.iter().find(|(name, _)| *name == key) {
+                    Some((_, expected)) => Err(anyhow!(
+                        "`{}` must be a {}, got {}",
+                        key,
+                        expected,
+                        value.type_str()
+                    )),
+                    None => Err(unknown_key(key, KEYS.iter().map(|(name, _)| *name))),
+                };
             }
         }
+        Ok(())
+    }
+}
+
+/// Parses a size in bytes, either an integer or a string like `512K` or `2M`
+fn parse_size(value: &Value) -> Result<u64> {
+    let invalid = || {
+        anyhow!(
+            "expected a size like 4096, \"512K\" or \"2M\", got {}",
+            value
+        )
+    };
+    match value {
+        Value::Integer(bytes) => u64::try_from(*bytes).map_err(|_| invalid()),
+        Value::String(size) => {
+            let (digits, shift) = match size.char_indices().last() {
+                Some((i, 'K')) => (&size[..i], 10),
+                Some((i, 'M')) => (&size[..i], 20),
+                Some((i, 'G')) => (&size[..i], 30),
+                _ => (size.as_str(), 0),
+            };
+            digits
+                .parse::<u64>()
+                .ok()
+                .and_then(|size| size.checked_mul(1 << shift))
+                .ok_or_else(invalid)
+        }
+        _ => Err(invalid()),
     }
-    Ok(config.into())
 }
 
 fn parse_string_array(array: Vec<Value>, prop_name: &str) -> Result<Vec<String>> {
@@ -123,47 +1304,374 @@ fn parse_string_array(array: Vec<Value>, prop_name: &str) -> Result<Vec<String>>
     Ok(parsed)
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct ConfigBuilder {
     build_command: Option<Vec<String>>,
+    kernel_cargo_args: Option<Vec<String>>,
+    bootloader_cargo_args: Option<Vec<String>>,
     run_command: Option<Vec<String>>,
     run_args: Option<Vec<String>>,
     test_args: Option<Vec<String>>,
     test_timeout: Option<u32>,
     test_success_exit_code: Option<i32>,
+    test_no_reboot: Option<bool>,
     debug_run_command: Option<Vec<String>>,
+    symbolize_backtraces: Option<bool>,
+    backtrace_regex: Option<String>,
+    diagnostics: Option<bool>,
+    diagnostics_log_items: Option<String>,
+    diagnostics_log: Option<PathBuf>,
+    qmp: Option<bool>,
+    test_screenshots: Option<bool>,
+    test_screenshot_interval: Option<u32>,
+    display: Option<Display>,
+    test_display: Option<Display>,
+    bootloader: Option<Bootloader>,
+    bootloader_crate: Option<String>,
+    bootloader_path: Option<PathBuf>,
+    bootloader_features: Option<Vec<String>>,
+    bootloader_kernel_env: Option<String>,
+    bootloader_glue: Option<Glue>,
+    bootloader_section: Option<String>,
+    symbol_store: Option<PathBuf>,
+    debug_compression: Option<Compression>,
+    strip_kernel: Option<bool>,
+    size_report: Option<bool>,
+    reproducible: Option<bool>,
+    dist_name: Option<String>,
+    dist_format: Option<DistFormat>,
+    size_budget: BTreeMap<String, u64>,
+    qemu: Option<QemuConfig>,
+    sources: BTreeMap<String, String>,
 }
 
 impl From<ConfigBuilder> for Config {
     fn from(s: ConfigBuilder) -> Config {
+        if s.qemu.is_some() && s.run_command.is_some() {
+            log::warn!("Both `run-command` and a `qemu` table are set, using `run-command`");
+        }
+        let qemu = s.qemu.unwrap_or_default();
         Config {
             build_command: s.build_command.unwrap_or_else(|| vec!["build".into()]),
-            debug_run_command: s.debug_run_command.unwrap_or_else(|| {
-                vec![
-                    "qemu-system-x86_64".into(),
-                    "-cdrom".into(),
-                    "{}".into(),
-                    "-serial".into(),
-                    "stdio".into(),
-                    "-no-reboot".into(),
-                    "-s".into(),
-                    "-S".into(),
-                ]
-            }),
-            run_command: s.run_command.unwrap_or_else(|| {
-                vec![
-                    "qemu-system-x86_64".into(),
-                    "-cdrom".into(),
-                    "{}".into(),
-                    "-serial".into(),
-                    "stdio".into(),
-                    "-no-reboot".into(),
-                ]
-            }),
+            kernel_cargo_args: s.kernel_cargo_args.unwrap_or_default(),
+            bootloader_cargo_args: s.bootloader_cargo_args.unwrap_or_default(),
+            debug_run_command: s.debug_run_command.unwrap_or_else(|| qemu.command(true)),
+            run_command: s.run_command.unwrap_or_else(|| qemu.command(false)),
+            qemu,
             run_args: s.run_args,
-            test_args: s.test_args.or_else(|| Some(vec!["-no-reboot".into()])),
+            test_args: s.test_args,
             test_timeout: s.test_timeout.unwrap_or(60 * 5),
             test_success_exit_code: s.test_success_exit_code,
+            test_no_reboot: s.test_no_reboot.unwrap_or(true),
+            symbolize_backtraces: s.symbolize_backtraces.unwrap_or(true),
+            backtrace_regex: s
+                .backtrace_regex
+                .unwrap_or_else(|| crate::symbolize::DEFAULT_BACKTRACE_REGEX.into()),
+            diagnostics: s.diagnostics.unwrap_or(false),
+            diagnostics_log_items: s
+                .diagnostics_log_items
+                .unwrap_or_else(|| "int,cpu_reset".into()),
+            diagnostics_log: s.diagnostics_log,
+            qmp: s.qmp.unwrap_or(true),
+            test_screenshots: s.test_screenshots.unwrap_or(true),
+            test_screenshot_interval: s.test_screenshot_interval.unwrap_or(500),
+            display: s.display,
+            test_display: s.test_display.unwrap_or(Display::None),
+            bootloader: s.bootloader.unwrap_or(Bootloader::Crate),
+            bootloader_crate: s.bootloader_crate.unwrap_or_else(|| "bootloader".into()),
+            bootloader_path: s.bootloader_path,
+            bootloader_features: s
+                .bootloader_features
+                .unwrap_or_else(|| vec!["binary".into()]),
+            bootloader_kernel_env: s.bootloader_kernel_env.unwrap_or_else(|| "KERNEL".into()),
+            bootloader_glue: s.bootloader_glue.unwrap_or(Glue::Env),
+            bootloader_section: s.bootloader_section.unwrap_or_else(|| ".kernel".into()),
+            symbol_store: s.symbol_store,
+            debug_compression: s.debug_compression.unwrap_or(Compression::None),
+            strip_kernel: s.strip_kernel.unwrap_or(false),
+            size_report: s.size_report.unwrap_or(false),
+            reproducible: s.reproducible.unwrap_or(false),
+            dist_name: s
+                .dist_name
+                .unwrap_or_else(|| "{crate_name}-{version}-{git_describe}".into()),
+            dist_format: s.dist_format.unwrap_or(DistFormat::TarZst),
+            size_budget: s.size_budget,
+            sources: s.sources,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_manifest(name: &str, content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glue_gun-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Cargo.toml");
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn profile(name: &str) -> ConfigOptions {
+        ConfigOptions {
+            profile: Some(name.into()),
+            overrides: Vec::new(),
         }
     }
+
+    #[test]
+    fn profile_inherits_base() {
+        let manifest = write_manifest(
+            "profiles",
+            r#"
+            [package]
+            name = "kernel"
+
+            [package.metadata.glue_gun]
+            test-timeout = 30
+
+            [package.metadata.glue_gun.qemu]
+            memory = "1G"
+            accel = "kvm"
+
+            [package.metadata.glue_gun.profiles.ci.qemu]
+            accel = "tcg"
+            smp = 8
+            "#,
+        );
+
+        let base = read_config(&manifest, &ConfigOptions::default()).unwrap();
+        assert_eq!(base.qemu.accel, ["kvm"]);
+
+        let ci = read_config(&manifest, &profile("ci")).unwrap();
+        assert_eq!(ci.test_timeout, 30);
+        assert_eq!(ci.qemu.memory.as_deref(), Some("1G"));
+        assert_eq!(ci.qemu.accel, ["tcg"]);
+        assert_eq!(ci.qemu.smp.as_deref(), Some("8"));
+
+        let err = read_config(&manifest, &profile("kvm")).unwrap_err();
+        assert!(format!("{:#}", err).contains("available profiles: [ci]"));
+        let profiles = read_profiles(&manifest, &ConfigOptions::default()).unwrap();
+        assert_eq!(profiles.len(), 1);
+    }
+
+    #[test]
+    fn ignores_unrelated_env_vars() {
+        let vars = [
+            ("GLUE_GUN_TEST_TIMEOUT", "60"),
+            ("GLUE_GUN_QEMU__MEMORY", "2G"),
+            ("GLUE_GUN_SIZE_BUDGET__ISO", "512K"),
+            ("GLUE_GUN_PROFILE", "ci"),
+            ("GLUE_GUN_CACHE_DIR", "/tmp/cache"),
+            ("GLUE_GUN_QEMU__CACHE", "on"),
+            ("GLUE_GUN_QEMU", "qemu-system-x86_64"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let sources: Vec<String> = env_layers_from(vars)
+            .into_iter()
+            .map(|layer| layer.source)
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                "environment variable GLUE_GUN_QEMU__MEMORY",
+                "environment variable GLUE_GUN_SIZE_BUDGET__ISO",
+                "environment variable GLUE_GUN_TEST_TIMEOUT",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_size_budget() {
+        let manifest = write_manifest(
+            "size-budget",
+            r#"
+            [package]
+            name = "kernel"
+
+            [package.metadata.glue_gun.size-budget]
+            kernel = { text = "512K" }
+            bootloader.text = "64K"
+            iso = 1048576
+            "#,
+        );
+        let options = ConfigOptions {
+            profile: None,
+            overrides: vec!["size-budget.kernel.rodata=2M".into()],
+        };
+        let config = read_config(&manifest, &options).unwrap();
+        assert_eq!(config.size_budget["kernel.text"], 512 << 10);
+        assert_eq!(config.size_budget["bootloader.text"], 64 << 10);
+        assert_eq!(config.size_budget["iso"], 1 << 20);
+        assert_eq!(config.size_budget["kernel.rodata"], 2 << 20);
+        assert_eq!(
+            config.sources["size-budget.kernel.rodata"],
+            "--config size-budget.kernel.rodata=2M"
+        );
+        let shown = config.show();
+        let line = shown
+            .lines()
+            .find(|line| line.starts_with("size-budget.kernel.rodata = 2097152 "))
+            .unwrap();
+        assert!(line.ends_with("# --config size-budget.kernel.rodata=2M"));
+
+        let options = ConfigOptions {
+            profile: None,
+            overrides: vec!["size-budget.kernel.data=2X".into()],
+        };
+        let err = read_config(&manifest, &options).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("expected a size"),
+            "{:#}",
+            err
+        );
+
+        let options = ConfigOptions {
+            profile: None,
+            overrides: vec!["size-budget.text=2M".into()],
+        };
+        let err = read_config(&manifest, &options).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unknown size budget `text`"),
+            "{:#}",
+            err
+        );
+
+        let options = ConfigOptions {
+            profile: None,
+            overrides: vec!["size-budget.kernel=1".into()],
+        };
+        let err = read_config(&manifest, &options).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("expected a table of section budgets"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn layers_override_in_order() {
+        let manifest = write_manifest(
+            "layers",
+            r#"
+            [package]
+            name = "kernel"
+
+            [package.metadata.glue_gun]
+            test-timeout = 30
+            qmp = false
+
+            [package.metadata.glue_gun.qemu]
+            memory = "1G"
+            "#,
+        );
+        std::fs::write(
+            manifest.with_file_name("glue_gun.toml"),
+            "test-timeout = 10\nqemu = { accel = \"kvm\" }\n",
+        )
+        .unwrap();
+
+        let options = ConfigOptions {
+            profile: None,
+            overrides: vec!["qemu.memory=2G".into(), "qmp=true".into()],
+        };
+        let config = read_config(&manifest, &options).unwrap();
+        assert_eq!(config.test_timeout, 10);
+        assert_eq!(config.qemu.memory.as_deref(), Some("2G"));
+        assert_eq!(config.qemu.accel, ["kvm"]);
+        assert!(config.qmp);
+        assert_eq!(config.sources["qemu.memory"], "--config qemu.memory=2G");
+        assert!(config.sources["test-timeout"].ends_with("glue_gun.toml"));
+
+        let show = config.show();
+        assert!(show.contains("qemu.accel = [\"kvm\"]"), "{}", show);
+        assert!(show
+            .lines()
+            .any(|l| l.starts_with("test-display") && l.ends_with("# default")));
+
+        let err = read_config(
+            &manifest,
+            &ConfigOptions {
+                profile: None,
+                overrides: vec!["test-timeout".into()],
+            },
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("key=value"));
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        let manifest = write_manifest(
+            "diagnostics",
+            r#"
+[package]
+name = "kernel"
+
+[package.metadata.glue_gun]
+test-timout = 30
+test-success-exit-code = 512
+test-no-reboot = false
+
+[package.metadata.glue_gun.qemu]
+memroy = "1G"
+"#,
+        );
+        let err = read_config(&manifest, &ConfigOptions::default()).unwrap_err();
+        let errors = err.downcast_ref::<ConfigErrors>().unwrap();
+        let path = manifest.display();
+        assert_eq!(
+            errors.0,
+            [
+                Diagnostic {
+                    location: format!("{}:11:1", path),
+                    message: "unknown key `memroy`, did you mean `memory`?".into(),
+                },
+                Diagnostic {
+                    location: format!("{}:7:1", path),
+                    message: "`test-success-exit-code` must be between 0 and 255, got 512".into(),
+                },
+                Diagnostic {
+                    location: format!("{}:6:1", path),
+                    message: "unknown key `test-timout`, did you mean `test-timeout`?".into(),
+                },
+            ]
+        );
+
+        let config = read_config(
+            &manifest,
+            &ConfigOptions {
+                profile: None,
+                overrides: vec!["test-timeout=0".into()],
+            },
+        );
+        let message = format!("{:#}", config.unwrap_err());
+        assert!(
+            message.contains(
+                "--config test-timeout=0: `test-timeout` must be between 1 and 4294967295"
+            ),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn locates_keys() {
+        let text = "[package.metadata.glue_gun]\nqemu = { accel = \"kvm\" }\n\n[package.metadata.glue_gun.profiles.ci]\n  test-timeout = 1\n";
+        let glue_gun = ["package", "metadata", "glue_gun"];
+        assert_eq!(
+            locate(text, &[&glue_gun[..], &["qemu", "accel"]].concat()),
+            Some((2, 10))
+        );
+        assert_eq!(
+            locate(
+                text,
+                &[&glue_gun[..], &["profiles", "ci", "test-timeout"]].concat()
+            ),
+            Some((5, 3))
+        );
+        assert_eq!(locate(text, &["test-timeout"]), None);
+    }
 }
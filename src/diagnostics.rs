@@ -0,0 +1,279 @@
+//! Collects crash diagnostics from QEMU after a triple fault or a timeout
+//!
+//! QEMU is started with `-d int,cpu_reset -D <file>` which logs every
+//! interrupt together with a register dump. After QEMU exited the log is
+//! scanned for the last CPU exception and a short crash summary is printed.
+//! On a test timeout the registers are dumped through QMP instead.
+
+use crate::symbolize::Symbolizer;
+use log::*;
+use regex::Regex;
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Paths used by a diagnostic QEMU session
+#[derive(Debug, Clone)]
+pub struct DiagnosticFiles {
+    /// The file QEMU writes its `-d` log to
+    pub log: PathBuf,
+}
+
+impl DiagnosticFiles {
+    /// Places the diagnostic files next to the disk image
+    pub fn new(image_path: &Path, log: Option<&Path>) -> Self {
+        let stem = image_path.file_stem().unwrap().to_str().unwrap();
+        let dir = image_path.parent().unwrap();
+        Self {
+            log: log
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| dir.join(format!("{}.qemu.log", stem))),
+        }
+    }
+
+    /// Returns the extra QEMU arguments for the diagnostic mode
+    pub fn qemu_args(&self, log_items: &str) -> Vec<String> {
+        vec![
+            "-d".to_string(),
+            log_items.to_string(),
+            "-D".to_string(),
+            self.log.display().to_string(),
+        ]
+    }
+
+    /// Removes the log of a previous session
+    pub fn reset(&self) {
+        let _ = std::fs::remove_file(&self.log);
+    }
+}
+
+/// A CPU exception logged by QEMU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exception {
+    pub vector: u8,
+    pub error_code: u64,
+    pub cr2: Option<u64>,
+}
+
+impl Exception {
+    pub fn name(&self) -> &'static str {
+        match self.vector {
+            0x00 => "#DE divide error",
+            0x01 => "#DB debug",
+            0x02 => "NMI",
+            0x03 => "#BP breakpoint",
+            0x04 => "#OF overflow",
+            0x05 => "#BR bound range exceeded",
+            0x06 => "#UD invalid opcode",
+            0x07 => "#NM device not available",
+            0x08 => "#DF double fault",
+            0x0a => "#TS invalid TSS",
+            0x0b => "#NP segment not present",
+            0x0c => "#SS stack-segment fault",
+            0x0d => "#GP general protection fault",
+            0x0e => "#PF page fault",
+            0x10 => "#MF x87 floating-point exception",
+            0x11 => "#AC alignment check",
+            0x12 => "#MC machine check",
+            0x13 => "#XM SIMD floating-point exception",
+            0x14 => "#VE virtualization exception",
+            0x15 => "#CP control protection exception",
+            _ => "reserved exception",
+        }
+    }
+}
+
+/// The condensed result of a QEMU log or register dump
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CrashReport {
+    /// The last CPU exception before the crash
+    pub exception: Option<Exception>,
+    /// Whether QEMU reported a triple fault
+    pub triple_fault: bool,
+    /// The register dump belonging to the exception
+    pub registers: Vec<String>,
+    /// The instruction pointer of the register dump
+    pub rip: Option<u64>,
+}
+
+impl CrashReport {
+    /// Parses a QEMU `-d int,cpu_reset` log
+    ///
+    /// Returns `None` if the log contains neither a CPU exception nor a triple fault.
+    pub fn from_log(log: &str) -> Option<Self> {
+        let interrupt =
+            Regex::new(r"^\s*\d+: v=([0-9a-f]+) e=([0-9a-f]+) .*?(?:CR2=([0-9a-f]+))?\s*$")
+                .unwrap();
+        let mut report = CrashReport::default();
+        let mut in_exception = false;
+
+        for line in log.lines() {
+            if let Some(caps) = interrupt.captures(line) {
+                let vector = u8::from_str_radix(&caps[1], 16).unwrap_or(0xff);
+                // Vectors above 31 are hardware or software interrupts
+                in_exception = vector < 0x20;
+                if in_exception {
+                    report.exception = Some(Exception {
+                        vector,
+                        error_code: u64::from_str_radix(&caps[2], 16).unwrap_or(0),
+                        cr2: caps
+                            .get(3)
+                            .and_then(|m| u64::from_str_radix(m.as_str(), 16).ok()),
+                    });
+                    report.registers.clear();
+                    report.rip = None;
+                }
+            } else if line.trim() == "Triple fault" {
+                report.triple_fault = true;
+                in_exception = false;
+            } else if in_exception && is_register_line(line) {
+                report.add_register_line(line);
+            } else if !line.starts_with(' ') {
+                in_exception = false;
+            }
+        }
+
+        (report.exception.is_some() || report.triple_fault).then_some(report)
+    }
+
+    /// Parses the output of the `info registers` monitor command
+    pub fn from_register_dump(dump: &str) -> Self {
+        let mut report = CrashReport::default();
+        for line in dump.lines().filter(|l| is_register_line(l)) {
+            report.add_register_line(line);
+        }
+        report
+    }
+
+    fn add_register_line(&mut self, line: &str) {
+        if let Some(rip) = line
+            .strip_prefix("RIP=")
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        {
+            self.rip = Some(rip);
+        }
+        self.registers.push(line.trim_end().to_string());
+    }
+
+    /// Formats the crash summary, resolving RIP with the given symbolizer
+    pub fn summary(&self, symbolizer: Option<&Symbolizer>) -> String {
+        let mut out = String::new();
+        if let Some(exception) = &self.exception {
+            let _ = write!(
+                out,
+                "  last exception: {} (vector {:#04x}) error code {:#x}",
+                exception.name(),
+                exception.vector,
+                exception.error_code
+            );
+            if let Some(cr2) = exception.cr2.filter(|_| exception.vector == 0x0e) {
+                let _ = write!(out, " CR2={:#x}", cr2);
+            }
+            out.push('\n');
+        }
+        if self.triple_fault {
+            out.push_str("  triple fault: CPU was reset\n");
+        }
+        if let Some(rip) = self.rip {
+            let _ = write!(out, "  RIP={:#018x}", rip);
+            let frames = symbolizer.map(|s| s.resolve(rip)).unwrap_or_default();
+            match frames.first() {
+                Some(frame) => {
+                    let _ = writeln!(out, " {}", frame.location());
+                }
+                None => out.push('\n'),
+            }
+        }
+        for line in &self.registers {
+            let _ = writeln!(out, "    {}", line);
+        }
+        out
+    }
+}
+
+fn is_register_line(line: &str) -> bool {
+    let line = line.trim_start();
+    let register = line.split('=').next().unwrap_or("");
+    line.contains('=')
+        && !register.is_empty()
+        && register.len() <= 4
+        && register
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == ' ')
+}
+
+/// Reads the QEMU log and prints a crash summary
+///
+/// The summary is printed if QEMU exited abnormally or the log contains a
+/// triple fault, exceptions during a clean run are expected.
+pub fn report_crash(
+    files: &DiagnosticFiles,
+    symbolizer: Option<&Mutex<Symbolizer>>,
+    abnormal_exit: bool,
+) {
+    let log = match std::fs::read_to_string(&files.log) {
+        Ok(log) => log,
+        Err(e) => {
+            warn!("Failed to read QEMU log {}: {}", files.log.display(), e);
+            return;
+        }
+    };
+    let report = CrashReport::from_log(&log).filter(|r| abnormal_exit || r.triple_fault);
+    if let Some(report) = report {
+        eprintln!(
+            "QEMU crash summary (full log at {}):\n{}",
+            files.log.display(),
+            report.summary(symbolizer.map(|s| s.lock().unwrap()).as_deref())
+        );
+    }
+}
+
+/// Prints a summary of a register dump taken from a hanging QEMU
+pub fn report_timeout(dump: &str, symbolizer: Option<&Mutex<Symbolizer>>) {
+    let report = CrashReport::from_register_dump(dump);
+    let symbolizer = symbolizer.map(|s| s.lock().unwrap());
+    eprintln!(
+        "QEMU state at timeout:\n{}",
+        report.summary(symbolizer.as_deref())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+check_exception old: 0xffffffff new 0xe
+     0: v=0e e=0002 i=0 cpl=0 IP=0008:ffffffff80001234 pc=ffffffff80001234 SP=0010:ffffffff80100f00 CR2=00000000deadbeef
+RAX=0000000000000000 RBX=0000000000000001 RCX=0000000000000002 RDX=0000000000000003
+RIP=ffffffff80001234 RFL=00000046 [---Z-P-] CPL=0 II=0 A20=1 SMM=0 HLT=0
+check_exception old: 0xe new 0xd
+     1: v=08 e=0000 i=0 cpl=0 IP=0008:ffffffff80001234 pc=ffffffff80001234 SP=0010:ffffffff80100f00 env->regs[R_EAX]=0000000000000000
+RAX=0000000000000000 RBX=0000000000000001 RCX=0000000000000002 RDX=0000000000000003
+RIP=ffffffff80001238 RFL=00000046 [---Z-P-] CPL=0 II=0 A20=1 SMM=0 HLT=0
+check_exception old: 0x8 new 0xd
+Triple fault
+CPU Reset (CPU 0)
+RAX=0000000000000000 RBX=0000000000000000 RCX=0000000000000000 RDX=0000000000000663
+     2: v=20 e=0000 i=0 cpl=0 IP=0008:0000000000001000 pc=0000000000001000 SP=0010:0000000000000000
+RIP=0000000000001000 RFL=00000002 [-------] CPL=0 II=0 A20=1 SMM=0 HLT=0
+";
+
+    #[test]
+    fn parses_last_exception() {
+        let report = CrashReport::from_log(LOG).unwrap();
+        assert!(report.triple_fault);
+        let exception = report.exception.unwrap();
+        assert_eq!(exception.vector, 0x08);
+        assert_eq!(report.rip, Some(0xffffffff80001238));
+        assert_eq!(report.registers.len(), 2);
+    }
+
+    #[test]
+    fn ignores_clean_log() {
+        assert_eq!(CrashReport::from_log("CPU Reset (CPU 0)\n"), None);
+    }
+}
@@ -0,0 +1,282 @@
+//! Release archives, for `glue_gun dist`
+//!
+//! Collects the ISO, the kernel without debug info, the symbol files, the
+//! bochs symbol file, the resolved configuration, the build manifest and the
+//! README of the kernel crate in `<target-dir>/dist/<name>/` and packs the
+//! directory into `<name>.tar.zst` or `<name>.zip`. `SHA256SUMS` in the
+//! archive lists the hashes of its files in the format of `sha256sum`,
+//! `<archive>.sha256` next to it the hash of the archive.
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    build::BuildMetadata,
+    config::{Compression, DistFormat},
+    Manifests,
+};
+
+/// The placeholders of `dist-name`
+const PLACEHOLDERS: [&str; 4] = ["crate_name", "version", "git_describe", "profile"];
+
+/// READMEs of the kernel crate, the first one found is included
+const READMES: [&str; 3] = ["README.md", "README", "README.txt"];
+
+/// Substitutes the placeholders of `dist-name`
+fn expand(template: &str, values: &[(&str, &str); 4]) -> Result<String> {
+    let mut name = template.to_string();
+    for (placeholder, value) in values {
+        name = name.replace(&format!("{{{}}}", placeholder), value);
+    }
+    if name.contains(['{', '}']) {
+        bail!(
+            "unknown placeholder in `dist-name = \"{}\"`, expected {}",
+            template,
+            PLACEHOLDERS
+                .iter()
+                .map(|placeholder| format!("{{{}}}", placeholder))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        bail!("`dist-name = \"{}\"` is not a file name", template);
+    }
+    Ok(name)
+}
+
+/// Writes `SHA256SUMS` for the files in `dir`
+fn write_checksums(dir: &Path) -> Result<()> {
+    let mut names: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<std::io::Result<_>>()?;
+    names.sort();
+    let mut sums = String::new();
+    for name in names {
+        let hash = crate::artifacts::sha256(&dir.join(&name))
+            .with_context(|| format!("Failed to hash {}", name))?;
+        sums.push_str(&format!("{}  {}\n", hash, name));
+    }
+    fs::write(dir.join("SHA256SUMS"), sums)?;
+    Ok(())
+}
+
+/// Returns the files of `dir` sorted by name, with whether they are executable
+fn entries(dir: &Path) -> Result<Vec<(String, PathBuf, bool)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        #[cfg(unix)]
+        let is_executable = {
+            use std::os::unix::fs::PermissionsExt;
+            entry.metadata()?.permissions().mode() & 0o111 != 0
+        };
+        #[cfg(not(unix))]
+        let is_executable = false;
+        entries.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            entry.path(),
+            is_executable,
+        ));
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Packs the files of `dir` below `prefix/` into a tarball
+fn tar(dir: &Path, prefix: &str, mtime: u64) -> Result<Vec<u8>> {
+    let mut tar = tar::Builder::new(Vec::new());
+    for (name, path, is_executable) in entries(dir)? {
+        let data = fs::read(&path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(if is_executable { 0o755 } else { 0o644 });
+        header.set_mtime(mtime);
+        header.set_cksum();
+        tar.append_data(&mut header, format!("{}/{}", prefix, name), data.as_slice())?;
+    }
+    Ok(tar.into_inner()?)
+}
+
+/// Packs the files of `dir` below `prefix/` into a zip archive
+fn zip(dir: &Path, prefix: &str) -> Result<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, path, is_executable) in entries(dir)? {
+        let options = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .unix_permissions(if is_executable { 0o755 } else { 0o644 });
+        zip.start_file(format!("{}/{}", prefix, name), options)?;
+        zip.write_all(&fs::read(&path)?)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Packs the artifacts of a release build, returns the path of the archive
+pub fn glue_gun_dist(artifacts: &BuildMetadata, manifests: &Manifests) -> Result<PathBuf> {
+    let config = &artifacts.config;
+    let kernel = &manifests.kernel;
+    let version = kernel
+        .meta
+        .get_crate_version()
+        .unwrap_or_else(|| "0.0.0".into());
+    let git_describe = crate::artifacts::git(
+        &kernel.crate_path,
+        &["describe", "--tags", "--always", "--dirty"],
+    )
+    .unwrap_or_else(|| {
+        warn!("`git describe` failed, using `unknown` in the archive name");
+        "unknown".into()
+    });
+    let name = expand(
+        &config.dist_name,
+        &[
+            ("crate_name", &artifacts.crate_name),
+            ("version", &version),
+            ("git_describe", &git_describe),
+            ("profile", &artifacts.profile),
+        ],
+    )?;
+
+    let dist_dir = kernel.target_dir.join("dist");
+    let dir = dist_dir.join(&name);
+    if dir.exists() {
+        fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let copy = |src: &Path| -> Result<()> {
+        let dst = dir.join(src.file_name().unwrap());
+        fs::copy(src, &dst)
+            .map(drop)
+            .with_context(|| format!("Failed to copy {}", src.display()))
+    };
+    copy(&artifacts.iso_img)?;
+    crate::sym::strip_copy(
+        &artifacts.kernel_elf,
+        &dir.join(artifacts.kernel_elf.file_name().unwrap()),
+        &artifacts.kernel_sym,
+    )
+    .with_context(|| format!("Failed to strip {}", artifacts.kernel_elf.display()))?;
+    copy(&artifacts.kernel_sym)?;
+    for path in artifacts.bootloader_sym.iter().chain(&artifacts.bochs_sym) {
+        copy(path)?;
+    }
+    copy(&artifacts.artifacts_json)?;
+    fs::write(dir.join("config.toml"), config.show())?;
+    if let Some(readme) = READMES
+        .iter()
+        .map(|readme| kernel.crate_path.join(readme))
+        .find(|readme| readme.is_file())
+    {
+        copy(&readme)?;
+    }
+    write_checksums(&dir)?;
+
+    let archive = dist_dir.join(format!("{}.{}", name, config.dist_format));
+    let data = match config.dist_format {
+        DistFormat::TarZst => {
+            let mtime = if config.reproducible {
+                crate::reproducible::source_date_epoch()?
+            } else {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs()
+            };
+            crate::sym::compress(&tar(&dir, &name, mtime)?, Compression::Zstd)
+                .context("Failed to compress the tarball")?
+        }
+        DistFormat::Zip => zip(&dir, &name)?,
+    };
+    let tmp = crate::staging::temp_path(&archive);
+    fs::write(&tmp, &data)
+        .and_then(|_| crate::staging::publish(&tmp, &archive))
+        .with_context(|| format!("Failed to write {}", archive.display()))?;
+    let file_name = archive.file_name().unwrap().to_string_lossy();
+    let hash = crate::artifacts::sha256(&archive)?;
+    fs::write(
+        dist_dir.join(format!("{}.sha256", file_name)),
+        format!("{}  {}\n", hash, file_name),
+    )?;
+    fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+
+    info!(
+        "Created {} ({})",
+        archive.display(),
+        crate::size::format_size(data.len() as u64)
+    );
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_names() {
+        let values = [
+            ("crate_name", "kernel"),
+            ("version", "0.1.0"),
+            ("git_describe", "v0.1.0-3-gdeadbee"),
+            ("profile", "release"),
+        ];
+        assert_eq!(
+            expand("{crate_name}-{version}-{git_describe}", &values).unwrap(),
+            "kernel-0.1.0-v0.1.0-3-gdeadbee"
+        );
+        assert_eq!(
+            expand("{crate_name}_{profile}", &values).unwrap(),
+            "kernel_release"
+        );
+        assert!(expand("{crate}-{version}", &values).is_err());
+        assert!(expand("../{crate_name}", &values).is_err());
+    }
+
+    #[test]
+    fn packs_archives() {
+        let dir = std::env::temp_dir().join(format!("glue_gun-dist-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("kernel.iso"), "image").unwrap();
+        fs::write(dir.join("kernel.sym"), "symbols").unwrap();
+        write_checksums(&dir).unwrap();
+        let sums = fs::read_to_string(dir.join("SHA256SUMS")).unwrap();
+        assert_eq!(sums.lines().count(), 2);
+        assert!(sums.ends_with("  kernel.sym\n"), "{}", sums);
+
+        let tarball = tar(&dir, "kernel-0.1.0", 1700000000).unwrap();
+        assert_eq!(tarball, tar(&dir, "kernel-0.1.0", 1700000000).unwrap());
+        let compressed = crate::sym::compress(&tarball, Compression::Zstd).unwrap();
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), tarball);
+        let mut archive = tar::Archive::new(tarball.as_slice());
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "kernel-0.1.0/SHA256SUMS",
+                "kernel-0.1.0/kernel.iso",
+                "kernel-0.1.0/kernel.sym"
+            ]
+        );
+
+        let zipped = zip(&dir, "kernel-0.1.0").unwrap();
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zipped)).unwrap();
+        assert_eq!(archive.len(), 3);
+        let mut image = String::new();
+        std::io::Read::read_to_string(
+            &mut archive.by_name("kernel-0.1.0/kernel.iso").unwrap(),
+            &mut image,
+        )
+        .unwrap();
+        assert_eq!(image, "image");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
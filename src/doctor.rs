@@ -0,0 +1,520 @@
+//! Checks the environment for everything glue_gun needs, for `glue_gun doctor`
+//!
+//! Every external tool glue_gun invokes is looked up and asked for its
+//! version. Problems come with a fix hint for the detected distribution.
+
+use crate::config::{self, Bootloader, Config, ConfigOptions};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// How bad a failed check is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// glue_gun works, but a feature is unavailable
+    Warning,
+    /// glue_gun build or run will fail
+    Error,
+}
+
+/// The result of a single check
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    /// The version of a tool, or what is wrong
+    pub detail: String,
+    /// How to fix the problem
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn ok(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status: Status::Ok,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn failed(
+        status: Status,
+        name: impl Into<String>,
+        detail: impl Into<String>,
+        hint: Option<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            hint,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "[ok]   ",
+            Status::Warning => "[warn] ",
+            Status::Error => "[error]",
+        };
+        write!(f, "{} {:<16} {}", status, self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            for line in hint.lines() {
+                write!(f, "\n{:25}{}", "", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Linux distributions with known package names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distro {
+    Debian,
+    Fedora,
+    Arch,
+    NixOS,
+    Unknown,
+}
+
+impl Distro {
+    /// Detects the distribution from `/etc/os-release`
+    pub fn detect() -> Self {
+        std::fs::read_to_string("/etc/os-release")
+            .map(|os_release| Self::from_os_release(&os_release))
+            .unwrap_or(Distro::Unknown)
+    }
+
+    /// Parses the `ID` and `ID_LIKE` fields of an os-release file
+    fn from_os_release(os_release: &str) -> Self {
+        let mut ids = Vec::new();
+        for line in os_release.lines() {
+            if let Some(value) = line
+                .strip_prefix("ID=")
+                .or_else(|| line.strip_prefix("ID_LIKE="))
+            {
+                ids.extend(
+                    value
+                        .trim_matches('"')
+                        .split_whitespace()
+                        .map(str::to_owned),
+                );
+            }
+        }
+        for id in ids {
+            match id.as_str() {
+                "debian" | "ubuntu" => return Distro::Debian,
+                "fedora" | "rhel" | "centos" => return Distro::Fedora,
+                "arch" | "manjaro" => return Distro::Arch,
+                "nixos" => return Distro::NixOS,
+                _ => {}
+            }
+        }
+        Distro::Unknown
+    }
+}
+
+/// System packages providing a tool on each distribution
+struct Packages {
+    debian: &'static str,
+    fedora: &'static str,
+    arch: &'static str,
+    nix: &'static str,
+}
+
+impl Packages {
+    fn hint(&self, distro: Distro) -> String {
+        match distro {
+            Distro::Debian => format!("fix: sudo apt install {}", self.debian),
+            Distro::Fedora => format!("fix: sudo dnf install {}", self.fedora),
+            Distro::Arch => format!("fix: sudo pacman -S {}", self.arch),
+            Distro::NixOS => format!("fix: add {} to your nix shell", self.nix),
+            Distro::Unknown => format!(
+                "fix: install it with your package manager, for example\n\
+                 \x20 apt install {}\n  dnf install {}\n  pacman -S {}\n  nix-shell -p {}",
+                self.debian, self.fedora, self.arch, self.nix
+            ),
+        }
+    }
+}
+
+const GRUB: Packages = Packages {
+    debian: "grub-pc-bin grub-efi-amd64-bin grub-common",
+    fedora: "grub2-tools-extra grub2-pc-modules grub2-efi-x64-modules",
+    arch: "grub",
+    nix: "grub2",
+};
+const XORRISO: Packages = Packages {
+    debian: "xorriso",
+    fedora: "xorriso",
+    arch: "libisoburn",
+    nix: "xorriso",
+};
+const MTOOLS: Packages = Packages {
+    debian: "mtools",
+    fedora: "mtools",
+    arch: "mtools",
+    nix: "mtools",
+};
+
+const QEMU: Packages = Packages {
+    debian: "qemu-system-x86",
+    fedora: "qemu-system-x86",
+    arch: "qemu-system-x86",
+    nix: "qemu",
+};
+
+/// Runs `program --version` and returns the first line of its output
+fn version(program: &Path, dir: &Path) -> Result<String, String> {
+    let output = Command::new(program)
+        .arg("--version")
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("not found ({})", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(format!("`--version` failed: {}", stderr.trim()));
+    }
+    // Some tools, for example xorriso, print their version to stderr
+    Ok(stdout
+        .lines()
+        .chain(stderr.lines())
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("unknown version")
+        .trim()
+        .to_string())
+}
+
+/// Checks a tool that is invoked by name
+fn check_tool(
+    name: &str,
+    program: &Path,
+    dir: &Path,
+    missing: Status,
+    hint: impl FnOnce() -> String,
+) -> Check {
+    match version(program, dir) {
+        Ok(version) => Check::ok(name, version),
+        Err(detail) => Check::failed(missing, name, detail, Some(hint())),
+    }
+}
+
+fn check_toolchain(dir: &Path) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let cargo = PathBuf::from(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()));
+    checks.push(check_tool("cargo", &cargo, dir, Status::Error, || {
+        "fix: install rustup from https://rustup.rs".into()
+    }));
+
+    let rustc = Path::new("rustc");
+    match version(rustc, dir) {
+        Ok(version) if version.contains("nightly") => checks.push(Check::ok("rustc", version)),
+        Ok(version) => checks.push(Check::failed(
+            Status::Error,
+            "rustc",
+            format!("{} is not a nightly toolchain", version),
+            Some(
+                "fix: build-std needs nightly, add a rust-toolchain.toml with\n\
+                 \x20 [toolchain]\n  channel = \"nightly\""
+                    .into(),
+            ),
+        )),
+        Err(detail) => checks.push(Check::failed(
+            Status::Error,
+            "rustc",
+            detail,
+            Some("fix: install rustup from https://rustup.rs".into()),
+        )),
+    }
+
+    let sysroot = Command::new(rustc)
+        .args(["--print", "sysroot"])
+        .current_dir(dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+    if let Some(sysroot) = sysroot {
+        let library = sysroot.join("lib/rustlib/src/rust/library");
+        if library.is_dir() {
+            checks.push(Check::ok("rust-src", library.display().to_string()));
+        } else {
+            checks.push(Check::failed(
+                Status::Error,
+                "rust-src",
+                "component missing, build-std can't compile core",
+                Some("fix: rustup component add rust-src".into()),
+            ));
+        }
+    }
+
+    // Only a fallback for executables the in-process ELF rewriter rejects
+    let hint = "fix: rustup component add llvm-tools-preview";
+    match llvm_tools::LlvmTools::new() {
+        Ok(tools) => match tools.tool(&llvm_tools::exe("llvm-objcopy")) {
+            Some(objcopy) => checks.push(check_tool(
+                "llvm-objcopy",
+                &objcopy,
+                dir,
+                Status::Warning,
+                || hint.into(),
+            )),
+            None => checks.push(Check::failed(
+                Status::Warning,
+                "llvm-objcopy",
+                "not found in llvm-tools",
+                Some(hint.into()),
+            )),
+        },
+        Err(e) => checks.push(Check::failed(
+            Status::Warning,
+            "llvm-tools",
+            format!("not found ({:?})", e),
+            Some(hint.into()),
+        )),
+    }
+    checks
+}
+
+fn check_image_tools(dir: &Path, distro: Distro) -> Vec<Check> {
+    let mut grub = check_tool(
+        "grub-mkrescue",
+        Path::new("grub-mkrescue"),
+        dir,
+        Status::Error,
+        || GRUB.hint(distro),
+    );
+    if grub.status != Status::Ok && version(Path::new("grub2-mkrescue"), dir).is_ok() {
+        grub.hint = Some(
+            "fix: glue_gun calls grub-mkrescue, link it to grub2-mkrescue:\n\
+             \x20 sudo ln -s $(command -v grub2-mkrescue) /usr/local/bin/grub-mkrescue"
+                .into(),
+        );
+    }
+    vec![
+        grub,
+        check_tool("xorriso", Path::new("xorriso"), dir, Status::Error, || {
+            XORRISO.hint(distro)
+        }),
+        check_tool(
+            "mformat",
+            Path::new("mformat"),
+            dir,
+            Status::Warning,
+            || {
+                format!(
+                    "needed by grub-mkrescue for UEFI images\n{}",
+                    MTOOLS.hint(distro)
+                )
+            },
+        ),
+        check_tool(
+            "bochsym",
+            Path::new("bochsym"),
+            dir,
+            Status::Warning,
+            || "only needed for bochs symbol files, which are skipped without it".into(),
+        ),
+    ]
+}
+
+/// Validates the kernel manifest, its bootloader dependency and the configuration
+///
+/// Returns the checks and the QEMU binary from the run command.
+fn check_project(dir: &Path, options: &ConfigOptions) -> (Vec<Check>, Option<String>) {
+    let mut checks = Vec::new();
+    let cargo_toml = dir.join("Cargo.toml");
+    if !cargo_toml.is_file() {
+        checks.push(Check::failed(
+            Status::Error,
+            "kernel manifest",
+            format!("{} not found", cargo_toml.display()),
+            Some("fix: run glue_gun in the kernel crate or create one with `glue_gun init`".into()),
+        ));
+        return (checks, None);
+    }
+
+    let metadata = match cargo_metadata::MetadataCommand::new()
+        .manifest_path(&cargo_toml)
+        .no_deps()
+        .exec()
+    {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            checks.push(Check::failed(
+                Status::Error,
+                "kernel manifest",
+                e.to_string(),
+                None,
+            ));
+            return (checks, None);
+        }
+    };
+    let config = config::read_config(&cargo_toml, options);
+    match metadata.root_package() {
+        Some(kernel) => {
+            checks.push(Check::ok("kernel manifest", kernel.name.to_string()));
+            if let Ok(config) = &config {
+                checks.push(check_bootloader(kernel, dir, config));
+            }
+        }
+        None => checks.push(Check::failed(
+            Status::Error,
+            "kernel manifest",
+            format!("{} is a virtual manifest", cargo_toml.display()),
+            Some("fix: run glue_gun in the directory of the kernel crate".into()),
+        )),
+    }
+
+    match config {
+        Ok(config) => {
+            checks.push(Check::ok("configuration", "valid"));
+            let qemu = config.run_command.first().cloned();
+            (checks, qemu)
+        }
+        Err(e) => {
+            checks.push(Check::failed(
+                Status::Error,
+                "configuration",
+                format!("{:#}", e),
+                None,
+            ));
+            (checks, None)
+        }
+    }
+}
+
+/// Checks that the bootloader crate exists and has the configured features
+fn check_bootloader(kernel: &cargo_metadata::Package, dir: &Path, config: &Config) -> Check {
+    let name = "bootloader";
+    if config.bootloader == Bootloader::None {
+        return Check::ok(name, "none, the kernel is booted directly");
+    }
+    let path = match &config.bootloader_path {
+        Some(path) => dir.join(path),
+        None => {
+            let dependency = kernel
+                .dependencies
+                .iter()
+                .find(|dep| dep.rename.as_ref().unwrap_or(&dep.name) == &config.bootloader_crate);
+            match dependency.and_then(|dep| dep.path.as_ref()) {
+                Some(path) => path.clone().into_std_path_buf(),
+                None => {
+                    return Check::failed(
+                        Status::Error,
+                        name,
+                        format!(
+                            "no path dependency named `{}` in the kernel manifest",
+                            config.bootloader_crate
+                        ),
+                        Some(format!(
+                            "fix: add the bootloader crate as dependency:\n\
+                             \x20 {} = {{ path = \"bootloader\" }}\n\
+                             or set `bootloader-path` or `bootloader = \"none\"`",
+                            config.bootloader_crate
+                        )),
+                    )
+                }
+            }
+        }
+    };
+    let manifest = path.join("Cargo.toml");
+    let content = match std::fs::read_to_string(&manifest) {
+        Ok(content) => content,
+        Err(e) => {
+            return Check::failed(
+                Status::Error,
+                name,
+                format!("Failed to read {}: {}", manifest.display(), e),
+                None,
+            )
+        }
+    };
+    let features = content
+        .parse::<toml::Value>()
+        .ok()
+        .and_then(|manifest| manifest.get("features").cloned());
+    let missing: Vec<&str> = config
+        .bootloader_features
+        .iter()
+        .map(String::as_str)
+        .filter(|feature| features.as_ref().map_or(true, |f| f.get(feature).is_none()))
+        .collect();
+    if missing.is_empty() {
+        return Check::ok(name, path.display().to_string());
+    }
+    let hint = missing.iter().fold(
+        "fix: add to the bootloader manifest\n  [features]".to_string(),
+        |hint, feature| format!("{}\n  {} = []", hint, feature),
+    );
+    Check::failed(
+        Status::Error,
+        name,
+        format!(
+            "{} has no feature `{}` from `bootloader-features`",
+            manifest.display(),
+            missing.join("`, `")
+        ),
+        Some(hint),
+    )
+}
+
+/// Runs all checks for the kernel crate in `dir`
+pub fn run_checks(dir: &Path, options: &ConfigOptions) -> Vec<Check> {
+    let distro = Distro::detect();
+    let mut checks = check_toolchain(dir);
+    checks.extend(check_image_tools(dir, distro));
+    let (project, qemu) = check_project(dir, options);
+    let qemu = qemu.unwrap_or_else(|| "qemu-system-x86_64".into());
+    checks.push(check_tool(
+        "qemu",
+        Path::new(&qemu),
+        dir,
+        Status::Error,
+        || QEMU.hint(distro),
+    ));
+    checks.extend(project);
+    checks
+}
+
+/// Prints the result of all checks, returns whether none of them failed
+///
+/// Implements `glue_gun doctor`.
+pub fn glue_gun_doctor(dir: &Path, options: &ConfigOptions) -> bool {
+    let checks = run_checks(dir, options);
+    for check in &checks {
+        println!("{}", check);
+    }
+    let errors = checks
+        .iter()
+        .filter(|check| check.status == Status::Error)
+        .count();
+    if errors > 0 {
+        println!("\n{} problem(s) found", errors);
+    }
+    errors == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_distro() {
+        let ubuntu = "NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\n";
+        assert_eq!(Distro::from_os_release(ubuntu), Distro::Debian);
+        let rocky = "ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n";
+        assert_eq!(Distro::from_os_release(rocky), Distro::Fedora);
+        assert_eq!(Distro::from_os_release("ID=gentoo\n"), Distro::Unknown);
+
+        let hint = GRUB.hint(Distro::Arch);
+        assert_eq!(hint, "fix: sudo pacman -S grub");
+    }
+}
@@ -0,0 +1,321 @@
+//! Generates a minimal kernel and bootloader project for `glue_gun init`
+//!
+//! The project consists of a `no_std` kernel crate and a multiboot2
+//! bootloader crate in its `bootloader` directory. The bootloader embeds the
+//! kernel given by the `KERNEL` environment variable when built with the
+//! `binary` feature, which is what `glue_gun build` does.
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Templates shared by all architectures, paths are relative to the project
+const TEMPLATES: [(&str, &str); 15] = [
+    (".gitignore", "target/\n"),
+    (
+        "Cargo.toml",
+        include_str!("../templates/init/kernel/Cargo.toml.template"),
+    ),
+    (
+        ".cargo/config.toml",
+        include_str!("../templates/init/kernel/cargo-config.toml"),
+    ),
+    (
+        "rust-toolchain.toml",
+        include_str!("../templates/init/kernel/rust-toolchain.toml"),
+    ),
+    (
+        "build.rs",
+        include_str!("../templates/init/kernel/build.rs"),
+    ),
+    (
+        "linker.ld",
+        include_str!("../templates/init/kernel/linker.ld"),
+    ),
+    (
+        "src/main.rs",
+        include_str!("../templates/init/kernel/src/main.rs"),
+    ),
+    (
+        "bootloader/Cargo.toml",
+        include_str!("../templates/init/bootloader/Cargo.toml.template"),
+    ),
+    (
+        "bootloader/.cargo/config.toml",
+        include_str!("../templates/init/bootloader/cargo-config.toml"),
+    ),
+    (
+        "bootloader/build.rs",
+        include_str!("../templates/init/bootloader/build.rs"),
+    ),
+    (
+        "bootloader/linker.ld",
+        include_str!("../templates/init/bootloader/linker.ld"),
+    ),
+    (
+        "bootloader/src/main.rs",
+        include_str!("../templates/init/bootloader/src/main.rs"),
+    ),
+    (
+        "bootloader/src/lib.rs",
+        include_str!("../templates/init/bootloader/src/lib.rs"),
+    ),
+    (
+        "bootloader/src/serial.rs",
+        include_str!("../templates/init/bootloader/src/serial.rs"),
+    ),
+    (
+        "bootloader/src/elf.rs",
+        include_str!("../templates/init/bootloader/src/elf.rs"),
+    ),
+];
+
+/// The firmware the generated project is run with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    /// SeaBIOS, QEMU's default
+    Bios,
+    /// OVMF, GRUB boots the same image through its EFI loader
+    Uefi,
+}
+
+impl Firmware {
+    pub const VARIANTS: [&'static str; 2] = ["bios", "uefi"];
+}
+
+impl FromStr for Firmware {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bios" => Ok(Firmware::Bios),
+            "uefi" => Ok(Firmware::Uefi),
+            _ => Err(anyhow!(
+                "unknown firmware `{}`, expected one of {}",
+                s,
+                Firmware::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// The architecture of the generated kernel
+///
+/// Limited to the architectures GRUB can boot through multiboot2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    I686,
+}
+
+impl Arch {
+    pub const VARIANTS: [&'static str; 2] = ["x86_64", "i686"];
+
+    fn target_spec(self) -> &'static str {
+        match self {
+            Arch::X86_64 => include_str!("../templates/init/targets/x86_64.json"),
+            Arch::I686 => include_str!("../templates/init/targets/i686.json"),
+        }
+    }
+
+    fn boot_asm(self) -> &'static str {
+        match self {
+            Arch::X86_64 => include_str!("../templates/init/bootloader/src/boot_x86_64.s"),
+            Arch::I686 => include_str!("../templates/init/bootloader/src/boot_i686.s"),
+        }
+    }
+
+    fn qemu(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "qemu-system-x86_64",
+            Arch::I686 => "qemu-system-i386",
+        }
+    }
+}
+
+impl FromStr for Arch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "x86_64" => Ok(Arch::X86_64),
+            "i686" => Ok(Arch::I686),
+            _ => Err(anyhow!(
+                "unsupported architecture `{}`, expected one of {}",
+                s,
+                Arch::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Arch::X86_64 => "x86_64",
+            Arch::I686 => "i686",
+        })
+    }
+}
+
+/// Generates a kernel and bootloader project in `dir`
+///
+/// The kernel crate is named `name`, or after the directory. Existing files
+/// are never overwritten. Returns the paths of the created files.
+///
+/// Implements `glue_gun init`.
+pub fn glue_gun_init(
+    dir: &Path,
+    name: Option<&str>,
+    arch: Arch,
+    firmware: Firmware,
+) -> Result<Vec<PathBuf>> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Can't derive a crate name from {}", dir.display()))?
+            .to_string(),
+    };
+    validate_name(&name)?;
+    if firmware == Firmware::Uefi && arch != Arch::X86_64 {
+        bail!("UEFI is only supported for x86_64");
+    }
+
+    let target = format!("{}-none", arch);
+    let firmware_args = match firmware {
+        Firmware::Bios => "",
+        Firmware::Uefi => {
+            "# OVMF firmware, the path depends on the distribution's ovmf package\n\
+             extra-args = [\"-bios\", \"/usr/share/ovmf/OVMF.fd\"]\n"
+        }
+    };
+    let substitute = |template: &str| {
+        template
+            .replace("{{firmware}}\n", firmware_args)
+            .replace("{{name}}", &name)
+            .replace("{{target}}", &target)
+            .replace("{{qemu}}", arch.qemu())
+    };
+
+    let target_file = format!("{}.json", target);
+    let bootloader_target_file = format!("bootloader/{}.json", target);
+    let files: Vec<(&str, &str)> = TEMPLATES
+        .iter()
+        .copied()
+        .chain([
+            ("bootloader/src/boot.s", arch.boot_asm()),
+            (target_file.as_str(), arch.target_spec()),
+            (bootloader_target_file.as_str(), arch.target_spec()),
+        ])
+        .collect();
+
+    let existing: Vec<String> = files
+        .iter()
+        .filter(|(path, _)| dir.join(path).exists())
+        .map(|(path, _)| path.to_string())
+        .collect();
+    if !existing.is_empty() {
+        bail!(
+            "Refusing to overwrite existing files in {}: {}",
+            dir.display(),
+            existing.join(", ")
+        );
+    }
+
+    let mut created = Vec::with_capacity(files.len());
+    for (path, template) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap())
+            .with_context(|| format!("Failed to create {}", path.parent().unwrap().display()))?;
+        std::fs::write(&path, substitute(template))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        debug!("Created {}", path.display());
+        created.push(path);
+    }
+    info!(
+        "Created {} kernel `{}` in {}, boot it with `cargo run`",
+        arch,
+        name,
+        dir.display()
+    );
+    Ok(created)
+}
+
+/// Rejects names cargo doesn't accept or that clash with the bootloader crate
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        bail!(
+            "`{}` is not a valid crate name, use letters, digits, `_` and `-`",
+            name
+        );
+    }
+    if name == "bootloader" {
+        bail!("The kernel can't be named `bootloader`, that is the name of the bootloader crate");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_valid_project() {
+        let dir = std::env::temp_dir().join(format!("glue_gun-init-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let created =
+            glue_gun_init(&dir, Some("hello-kernel"), Arch::X86_64, Firmware::Uefi).unwrap();
+        assert!(created.contains(&dir.join("bootloader/x86_64-none.json")));
+
+        let options = crate::config::ConfigOptions::default();
+        let (manifests, config) = crate::get_crate_paths_in(dir.clone(), &options).unwrap();
+        assert_eq!(manifests.kernel.crate_name, "hello-kernel");
+        assert_eq!(manifests.bootloader.unwrap().crate_name, "bootloader");
+
+        assert_eq!(config.test_success_exit_code, Some(33));
+        assert!(config.run_command.iter().any(|arg| arg == "-bios"));
+
+        let overrides = |overrides: &[&str]| crate::config::ConfigOptions {
+            profile: None,
+            overrides: overrides.iter().map(|o| o.to_string()).collect(),
+        };
+        let (manifests, _) = crate::get_crate_paths_in(
+            dir.clone(),
+            &overrides(&["bootloader-crate=boot", "bootloader-path=bootloader"]),
+        )
+        .unwrap();
+        assert_eq!(manifests.bootloader.unwrap().crate_name, "bootloader");
+        let (manifests, _) =
+            crate::get_crate_paths_in(dir.clone(), &overrides(&["bootloader=none"])).unwrap();
+        assert!(manifests.bootloader.is_none());
+        let err = crate::config::read_config(
+            &manifests.kernel.cargo_toml,
+            &overrides(&["bootloader=grub"]),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("expected one of crate, none"),
+            "{:#}",
+            err
+        );
+
+        let err = glue_gun_init(&dir, None, Arch::X86_64, Firmware::Bios).unwrap_err();
+        assert!(err.to_string().contains("Refusing to overwrite"), "{}", err);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(glue_gun_init(&dir, Some("1kernel"), Arch::X86_64, Firmware::Bios).is_err());
+        assert!(glue_gun_init(&dir, Some("kernel"), Arch::I686, Firmware::Uefi).is_err());
+    }
+}
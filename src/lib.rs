@@ -8,12 +8,28 @@ use std::process::ExitCode;
 
 use std::{env, path::PathBuf};
 
+mod artifacts;
 mod build;
 mod clean;
 mod config;
+mod diagnostics;
+mod dist;
+mod doctor;
+mod init;
 mod metadata;
+mod placeholder;
+mod preflight;
+mod qemu;
+mod qmp;
+mod reproducible;
 mod run;
+mod screenshot;
+mod size;
+mod splice;
+mod staging;
 mod sym;
+mod symbol_store;
+mod symbolize;
 mod watch;
 
 pub fn create_cli() -> clap::Command {
@@ -37,6 +53,14 @@ pub fn create_cli() -> clap::Command {
                 .global(true)
                 .value_parser(value_parser!(PathBuf)),
         )
+        .arg(
+            Arg::new("test")
+                .help("Treats the kernel executable as test, detected from its symbols by default")
+                .long("test")
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .requires("kernel"),
+        )
         .arg(
             Arg::new("release")
                 .global(true)
@@ -46,10 +70,86 @@ pub fn create_cli() -> clap::Command {
                 .action(clap::ArgAction::SetTrue)
                 .conflicts_with("kernel"),
         )
+        .arg(
+            Arg::new("profile")
+                .global(true)
+                .help("Selects a run profile from package.metadata.glue_gun.profiles [env: GLUE_GUN_PROFILE]")
+                .long("profile")
+                .short('p'),
+        )
+        .arg(
+            Arg::new("message_format")
+                .global(true)
+                .help("Prints the build manifest to stdout with `json`")
+                .long("message-format")
+                .value_name("FMT")
+                .value_parser(["human", "json"])
+                .default_value("human"),
+        )
+        .arg(
+            Arg::new("config")
+                .global(true)
+                .help("Overrides a configuration key, for example `qemu.memory=2G`")
+                .long("config")
+                .value_name("KEY=VALUE")
+                .action(clap::ArgAction::Append),
+        )
         .disable_help_subcommand(true)
         .subcommand_required(true)
         .help_expected(true)
-        .subcommand(clap::Command::new("build").about("Builds the ISO file"))
+        .subcommand(
+            clap::Command::new("build")
+                .about("Builds the ISO file")
+                .arg(cargo_args()),
+        )
+        .subcommand(
+            clap::Command::new("size")
+                .about("Builds the ISO file and reports the sizes of the kernel and its crates")
+                .arg(cargo_args()),
+        )
+        .subcommand(
+            clap::Command::new("dist")
+                .about("Builds the ISO file in release mode and packs the release artifacts")
+                .arg(cargo_args()),
+        )
+        .subcommand(
+            clap::Command::new("verify-reproducible")
+                .about("Builds the ISO file twice in reproducible mode and compares the images")
+                .arg(cargo_args()),
+        )
+        .subcommand(
+            clap::Command::new("doctor")
+                .about("Checks the tools, manifests and configuration glue_gun needs"),
+        )
+        .subcommand(
+            clap::Command::new("init")
+                .about("Creates a new kernel and bootloader project")
+                .arg(
+                    Arg::new("dir")
+                        .help("Directory of the new project")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("name")
+                        .help("Name of the kernel crate, defaults to the directory name")
+                        .long("name"),
+                )
+                .arg(
+                    Arg::new("arch")
+                        .help("Target architecture of the kernel")
+                        .long("arch")
+                        .value_parser(init::Arch::VARIANTS)
+                        .default_value("x86_64"),
+                )
+                .arg(
+                    Arg::new("firmware")
+                        .help("Firmware QEMU boots the image with")
+                        .long("firmware")
+                        .value_parser(init::Firmware::VARIANTS)
+                        .default_value("bios"),
+                ),
+        )
         .subcommand(
             clap::Command::new("run")
                 .about("Builds and runs the ISO file")
@@ -60,10 +160,80 @@ pub fn create_cli() -> clap::Command {
                         .long("debug")
                         .action(clap::ArgAction::SetTrue)
                         .required(false),
+                )
+                .arg(
+                    Arg::new("display")
+                        .help("Selects the QEMU display backend")
+                        .long("display")
+                        .value_parser(config::Display::VARIANTS)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("diagnose")
+                        .help("Logs interrupts and prints a crash summary on abnormal exit")
+                        .long("diagnose")
+                        .action(clap::ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(cargo_args()),
+        )
+        .subcommand(
+            clap::Command::new("watch")
+                .about("Watches for changes in kernel and bootloader")
+                .arg(cargo_args()),
+        )
+        .subcommand(
+            clap::Command::new("profiles")
+                .about("Lists the run profiles with their resolved commands"),
+        )
+        .subcommand(
+            clap::Command::new("config")
+                .about("Inspects the layered configuration")
+                .arg_required_else_help(true)
+                .arg(
+                    Arg::new("show")
+                        .help("Prints the merged configuration and where each value is set")
+                        .long("show")
+                        .action(clap::ArgAction::SetTrue),
                 ),
         )
         .subcommand(
-            clap::Command::new("watch").about("Watches for changes in kernel and bootloader"),
+            clap::Command::new("symbolize")
+                .about("Symbolizes a log with the archived symbols of past builds")
+                .arg(
+                    Arg::new("build_id")
+                        .help("Build-id of an executable that printed the log, logged by every build")
+                        .long("build-id")
+                        .value_name("BUILD_ID")
+                        .required(true)
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("log")
+                        .help("The log file, read from stdin if omitted")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("qmp")
+                .about("Sends a command to a running QEMU session")
+                .arg(
+                    Arg::new("socket")
+                        .help("Path to the QMP socket printed by `glue_gun run`")
+                        .short('s')
+                        .long("socket")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("command")
+                        .help(
+                            "One of: pause, resume, status, registers, screendump <file>, \
+                             snapshot-save <name>, snapshot-load <name>, powerdown, quit",
+                        )
+                        .required(true)
+                        .num_args(1..),
+                ),
         )
         .subcommand(
             clap::Command::new("clean")
@@ -79,18 +249,55 @@ pub fn create_cli() -> clap::Command {
         )
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Arguments after `--`, passed to cargo when building the kernel
+fn cargo_args() -> Arg {
+    Arg::new("cargo_args")
+        .help("Arguments passed to cargo when building the kernel")
+        .value_name("CARGO_ARGS")
+        .num_args(0..)
+        .allow_hyphen_values(true)
+        .last(true)
+}
+
+#[derive(Debug, Clone)]
 pub struct CliOptions {
     is_release: bool,
     is_verbose: bool,
     is_very_verbose: bool,
+    config: config::ConfigOptions,
+    /// Arguments after `--`, passed to cargo when building the kernel
+    cargo_args: Vec<String>,
+    /// Whether the size report is printed regardless of `size-report`
+    size_report: bool,
+    /// Whether the build manifest is printed to stdout, `--message-format json`
+    json_messages: bool,
 }
 
 pub async fn parse_matches(matches: &ArgMatches) -> Result<(), ExitCode> {
     let cli_options = CliOptions {
-        is_release: matches.get_flag("release"),
+        is_release: matches.get_flag("release") || matches.subcommand_matches("dist").is_some(),
         is_verbose: matches.get_count("verbose") >= 1,
         is_very_verbose: matches.get_count("verbose") > 1,
+        config: config::ConfigOptions {
+            profile: matches
+                .get_one::<String>("profile")
+                .cloned()
+                .or_else(|| env::var("GLUE_GUN_PROFILE").ok()),
+            overrides: matches
+                .get_many::<String>("config")
+                .map(|overrides| overrides.cloned().collect())
+                .unwrap_or_default(),
+        },
+        cargo_args: matches
+            .subcommand()
+            .and_then(|(_, matches)| matches.try_get_many::<String>("cargo_args").ok())
+            .flatten()
+            .map(|args| args.cloned().collect())
+            .unwrap_or_default(),
+        size_report: matches.subcommand_matches("size").is_some(),
+        json_messages: matches
+            .get_one::<String>("message_format")
+            .is_some_and(|format| format == "json"),
     };
 
     if cli_options.is_verbose {
@@ -98,7 +305,97 @@ pub async fn parse_matches(matches: &ArgMatches) -> Result<(), ExitCode> {
     }
     debug!("Args: {:?}", std::env::args());
 
-    let manifests = get_crate_paths();
+    if let Some(matches) = matches.subcommand_matches("qmp") {
+        let socket = matches.get_one::<PathBuf>("socket").unwrap();
+        let command: Vec<String> = matches
+            .get_many::<String>("command")
+            .unwrap()
+            .cloned()
+            .collect();
+        if let Err(e) = crate::qmp::glue_gun_qmp(socket, &command).await {
+            error!("{}", e);
+            return Err(ExitCode::FAILURE);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("init") {
+        let result = init::glue_gun_init(
+            matches.get_one::<PathBuf>("dir").unwrap(),
+            matches.get_one::<String>("name").map(String::as_str),
+            matches.get_one::<String>("arch").unwrap().parse().unwrap(),
+            matches
+                .get_one::<String>("firmware")
+                .unwrap()
+                .parse()
+                .unwrap(),
+        );
+        if let Err(e) = result {
+            error!("{:#}", e);
+            return Err(ExitCode::FAILURE);
+        }
+        return Ok(());
+    }
+
+    if matches.subcommand_matches("doctor").is_some() {
+        if doctor::glue_gun_doctor(&kernel_crate_dir(), &cli_options.config) {
+            return Ok(());
+        }
+        return Err(ExitCode::FAILURE);
+    }
+
+    let (manifests, config) = match get_crate_paths(&cli_options.config) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("{:#}", e);
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    if matches.subcommand_matches("profiles").is_some() {
+        let profiles =
+            match config::read_profiles(&manifests.kernel.cargo_toml, &cli_options.config) {
+                Ok(profiles) => profiles,
+                Err(e) => {
+                    error!("{:#}", e);
+                    return Err(ExitCode::FAILURE);
+                }
+            };
+        if profiles.is_empty() {
+            println!("No profiles defined");
+        }
+        for (name, config) in profiles {
+            println!("{}", name);
+            println!("  run:   {}", config.run_command.join(" "));
+            println!("  debug: {}", config.debug_run_command.join(" "));
+        }
+        return Ok(());
+    }
+
+    if matches.subcommand_matches("config").is_some() {
+        print!("{}", config.show());
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("symbolize") {
+        let build_ids: Vec<String> = matches
+            .get_many::<String>("build_id")
+            .unwrap()
+            .cloned()
+            .collect();
+        let result = symbolize::glue_gun_symbolize(
+            &symbol_store::location(&config, &manifests.kernel),
+            &build_ids,
+            matches.get_one::<PathBuf>("log").map(PathBuf::as_path),
+            &config.backtrace_regex,
+        )
+        .await;
+        if let Err(e) = result {
+            error!("{:#}", e);
+            return Err(ExitCode::FAILURE);
+        }
+        return Ok(());
+    }
 
     if let Some(matches) = matches.subcommand_matches("clean") {
         let is_all = matches.get_flag("all");
@@ -106,49 +403,100 @@ pub async fn parse_matches(matches: &ArgMatches) -> Result<(), ExitCode> {
         return Ok(());
     }
 
-    // If subcommand 'build' or 'run'
-    let kernel_exec_path: PathBuf = {
+    // If subcommand 'build', 'size', 'dist', 'verify-reproducible' or 'run'
+    let kernel: build::Artifact = {
         match matches.get_one::<PathBuf>("kernel") {
-            Some(path) => path.clone(),
+            Some(path) => {
+                if !cli_options.cargo_args.is_empty() {
+                    warn!("Kernel executable given, ignoring cargo arguments after `--`");
+                }
+                let is_test = matches.get_flag("test").then_some(true);
+                match build::Artifact::inspect(path, is_test) {
+                    Ok(kernel) => kernel,
+                    Err(e) => {
+                        error!("{:#}", e);
+                        return Err(ExitCode::FAILURE);
+                    }
+                }
+            }
             None => {
-                let kernel_path = crate::build::cargo_build(
+                let mut cargo_args = config.kernel_cargo_args.clone();
+                cargo_args.extend(cli_options.cargo_args.iter().cloned());
+                let artifacts = crate::build::cargo_build(
                     &manifests.kernel.crate_path,
                     None,
-                    cli_options.is_release,
+                    cli_options.is_release.then_some("release"),
                     cli_options.is_very_verbose,
                     None,
+                    &cargo_args,
                     None,
                 );
 
-                if kernel_path.len() != 1 {
+                if artifacts.len() != 1 {
                     panic!(
                         "Expected kernel to generate exactly one binary however {} habe been build",
-                        kernel_path.len()
+                        artifacts.len()
                     );
                 }
-                kernel_path.get(0).unwrap().clone()
+                artifacts.first().unwrap().clone()
             }
         }
     };
 
     if let Some(_matches) = matches.subcommand_matches("watch") {
-        crate::watch::glue_gun_watch(kernel_exec_path, manifests, cli_options).await;
+        crate::watch::glue_gun_watch(kernel, manifests, cli_options).await;
         return Ok(());
     }
 
-    let artifacts = crate::build::glue_gun_build(&kernel_exec_path, &manifests, &cli_options);
+    if matches.subcommand_matches("verify-reproducible").is_some() {
+        return match reproducible::glue_gun_verify_reproducible(
+            &kernel,
+            &manifests,
+            &config,
+            &cli_options,
+        ) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ExitCode::FAILURE),
+            Err(e) => {
+                error!("{:#}", e);
+                Err(ExitCode::FAILURE)
+            }
+        };
+    }
 
-    if let Some(matches) = matches.subcommand_matches("run") {
-        run::glue_gun_run(
-            artifacts.config,
-            &artifacts.iso_img,
-            artifacts.is_test,
-            matches.get_flag("debug"),
-        )
-        .unwrap();
+    let mut artifacts = crate::build::glue_gun_build(&kernel, &manifests, &config, &cli_options);
+
+    if matches.subcommand_matches("dist").is_some() {
+        if let Err(e) = dist::glue_gun_dist(&artifacts, &manifests) {
+            error!("{:#}", e);
+            return Err(ExitCode::FAILURE);
+        }
         return Ok(());
     }
 
+    if let Some(matches) = matches.subcommand_matches("run") {
+        let config = &mut artifacts.config;
+        config.diagnostics |= matches.get_flag("diagnose");
+        if let Some(display) = matches.get_one::<String>("display") {
+            let display = display.parse().unwrap();
+            config.display = Some(display);
+            config.test_display = display;
+        }
+        let result = run::glue_gun_run(&artifacts, matches.get_flag("debug")).await;
+        return match result {
+            Ok(0) => Ok(()),
+            Ok(code) => Err(exit_code(code)),
+            Err(e @ run::RunError::TestFailed { exit_code, .. }) => {
+                error!("{}", e);
+                Err(crate::exit_code(exit_code))
+            }
+            Err(e) => {
+                error!("{}", e);
+                Err(ExitCode::FAILURE)
+            }
+        };
+    }
+
     Ok(())
 }
 
@@ -164,18 +512,45 @@ pub struct Manifest {
 
 pub struct Manifests {
     kernel: Manifest,
-    bootloader: Manifest,
+    /// `None` if the kernel is booted without a bootloader crate
+    bootloader: Option<Manifest>,
+}
+
+/// Turns a failed exit code of the emulator into the exit code of glue_gun
+///
+/// Codes that don't fit, like 256, would otherwise be truncated to success.
+fn exit_code(code: i32) -> ExitCode {
+    u8::try_from(code)
+        .ok()
+        .filter(|code| *code != 0)
+        .map_or(ExitCode::FAILURE, ExitCode::from)
 }
 
-fn get_crate_paths() -> Manifests {
+/// Returns the directory of the kernel crate
+fn kernel_crate_dir() -> PathBuf {
+    env::var("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .or_else(|_| {
+            debug!("CARGO_MANIFEST_DIR not set. Using current directory");
+            std::env::current_dir()
+        })
+        .expect("Failed to a cargo manifest path")
+}
+
+fn get_crate_paths(options: &config::ConfigOptions) -> anyhow::Result<(Manifests, config::Config)> {
+    get_crate_paths_in(kernel_crate_dir(), options)
+}
+
+/// Finds the kernel crate in `kernel_crate_path` and its bootloader crate,
+/// returns them with the configuration of the kernel
+///
+/// The bootloader crate is located through the `bootloader-*` keys of the
+/// kernel configuration.
+fn get_crate_paths_in(
+    kernel_crate_path: PathBuf,
+    options: &config::ConfigOptions,
+) -> anyhow::Result<(Manifests, config::Config)> {
     let kernel_manifest = {
-        let kernel_crate_path: PathBuf = env::var("CARGO_MANIFEST_DIR")
-            .map(PathBuf::from)
-            .or_else(|_| {
-                debug!("CARGO_MANIFEST_DIR not set. Using current directory");
-                std::env::current_dir()
-            })
-            .expect("Failed to a cargo manifest path");
         if !kernel_crate_path.is_dir() {
             panic!(
                 "Manifest path does not point to a directory {}",
@@ -201,7 +576,7 @@ fn get_crate_paths() -> Manifests {
             );
         }
 
-        if !kernel_meta.metadata.workspace_metadata.is_null() {
+        if !is_glue_gun_only(&kernel_meta.metadata) {
             panic!(
                 "Workspace crates are not supported: {}",
                 kernel_cargo_toml.display()
@@ -216,8 +591,14 @@ fn get_crate_paths() -> Manifests {
         }
     };
 
-    let bootloader_manifest = {
-        let boot_crate_path = kernel_manifest.meta.get_crate_of_dependency("bootloader");
+    let config = config::read_config(&kernel_manifest.cargo_toml, options)?;
+    let bootloader_manifest = (config.bootloader == config::Bootloader::Crate).then(|| {
+        let boot_crate_path = match &config.bootloader_path {
+            Some(path) => kernel_manifest.crate_path.join(path),
+            None => kernel_manifest
+                .meta
+                .get_crate_of_dependency(&config.bootloader_crate),
+        };
         let boot_cargo_toml = boot_crate_path.join("Cargo.toml");
         if !boot_cargo_toml.is_file() {
             panic!("Couldn't find Cargo.toml in {}", boot_cargo_toml.display())
@@ -230,7 +611,7 @@ fn get_crate_paths() -> Manifests {
                 boot_names.len()
             );
         }
-        if !boot_meta.metadata.workspace_metadata.is_null() {
+        if !is_glue_gun_only(&boot_meta.metadata) {
             panic!(
                 "Workspace crates are not supported: {}",
                 boot_cargo_toml.display()
@@ -243,10 +624,20 @@ fn get_crate_paths() -> Manifests {
             target_dir: boot_meta.get_target_dir(),
             meta: boot_meta,
         }
-    };
+    });
 
-    Manifests {
+    let manifests = Manifests {
         bootloader: bootloader_manifest,
         kernel: kernel_manifest,
-    }
+    };
+    Ok((manifests, config))
+}
+
+/// Whether the `workspace.metadata` table is empty or only configures glue_gun
+fn is_glue_gun_only(metadata: &cargo_metadata::Metadata) -> bool {
+    let workspace_metadata = &metadata.workspace_metadata;
+    workspace_metadata.is_null()
+        || workspace_metadata
+            .as_object()
+            .is_some_and(|table| table.keys().all(|key| key == "glue_gun"))
 }
@@ -1,12 +1,10 @@
 #![allow(dead_code)]
 
 use log::*;
-
-mod config;
-mod run;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     simple_logger::SimpleLogger::new()
         .with_level(LevelFilter::Trace)
         .without_timestamps()
@@ -16,5 +14,8 @@ async fn main() {
 
     let app = glue_gun::create_cli();
     let matches = app.get_matches();
-    glue_gun::parse_matches(&matches).await.unwrap();
+    match glue_gun::parse_matches(&matches).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => code,
+    }
 }
@@ -37,18 +37,19 @@ impl CrateMetadata {
     pub fn get_crate_names(&self) -> Vec<String> {
         self.metadata
             .workspace_members
-            .clone()
-            .into_iter()
-            .map(|x| {
-                x.to_string()
-                    .split_ascii_whitespace()
-                    .next()
-                    .unwrap()
-                    .to_string()
-            })
+            .iter()
+            .map(|id| self.metadata[id].name.clone())
             .collect()
     }
 
+    /// Returns the version of the crate, the first workspace member
+    pub fn get_crate_version(&self) -> Option<String> {
+        self.metadata
+            .workspace_members
+            .first()
+            .map(|id| self.metadata[id].version.to_string())
+    }
+
     pub fn get_recurisve_local_dependencies(&self) -> BTreeSet<Dependency> {
         recursive_local_dependencies(&self.cargo_toml)
     }
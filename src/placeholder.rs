@@ -0,0 +1,314 @@
+//! Substitutes `{...}` placeholders in run commands
+//!
+//! Supported placeholders:
+//!
+//! - `{}` or `{image}`: the bootable disk image
+//! - `{kernel_elf}`: the kernel executable
+//! - `{kernel_sym}` and `{bootloader_sym}`: the symbol files
+//! - `{target_dir}`: the cargo target directory of the kernel
+//! - `{crate_name}`: the name of the kernel crate
+//! - `{tmp_dir}`: a temporary directory that is removed after the run
+//! - `{port:<name>}`: a free TCP port, the same name yields the same port
+//! - `{env:<VAR>}`: the value of an environment variable
+//!
+//! A literal brace is written as `{{` or `}}`.
+
+use log::*;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use thiserror::Error;
+
+/// Names of the placeholders that map to a fixed value
+const PATH_PLACEHOLDERS: [&str; 7] = [
+    "",
+    "image",
+    "kernel_elf",
+    "kernel_sym",
+    "bootloader_sym",
+    "target_dir",
+    "crate_name",
+];
+
+/// A placeholder could not be substituted
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PlaceholderError {
+    /// The placeholder name is not known
+    #[error("unknown placeholder `{{{placeholder}}}` in `{arg}`")]
+    Unknown { placeholder: String, arg: String },
+
+    /// A `{` has no matching `}`
+    #[error("unclosed placeholder in `{0}`, write `{{{{` for a literal brace")]
+    Unclosed(String),
+
+    /// An `{env:VAR}` placeholder references an unset variable
+    #[error("environment variable `{0}` referenced by `{{env:{0}}}` is not set")]
+    MissingEnv(String),
+
+    /// No free port or temporary directory could be allocated
+    #[error("failed to allocate `{{{placeholder}}}`: {error}")]
+    Allocation { placeholder: String, error: String },
+}
+
+/// Checks that `arg` only contains known placeholders
+pub fn validate(arg: &str) -> Result<(), PlaceholderError> {
+    for placeholder in parse(arg)?.into_iter().filter_map(Token::placeholder) {
+        if !is_known(placeholder) {
+            return Err(PlaceholderError::Unknown {
+                placeholder: placeholder.to_string(),
+                arg: arg.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn is_known(placeholder: &str) -> bool {
+    PATH_PLACEHOLDERS.contains(&placeholder)
+        || placeholder == "tmp_dir"
+        || placeholder
+            .strip_prefix("port:")
+            .or_else(|| placeholder.strip_prefix("env:"))
+            .is_some_and(|name| !name.is_empty())
+}
+
+/// The values placeholders are substituted with during one run
+#[derive(Debug, Default)]
+pub struct Placeholders {
+    values: BTreeMap<&'static str, String>,
+    ports: BTreeMap<String, u16>,
+    tmp_dir: Option<PathBuf>,
+}
+
+impl Placeholders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the value of a fixed placeholder like `image`
+    pub fn set(&mut self, name: &'static str, value: impl Into<String>) -> &mut Self {
+        debug_assert!(PATH_PLACEHOLDERS.contains(&name));
+        self.values.insert(name, value.into());
+        self
+    }
+
+    /// Sets the value of a fixed placeholder to a path
+    pub fn set_path(&mut self, name: &'static str, path: &Path) -> &mut Self {
+        self.set(name, path.display().to_string())
+    }
+
+    /// Substitutes all placeholders in `arg`
+    pub fn substitute(&mut self, arg: &str) -> Result<String, PlaceholderError> {
+        let mut out = String::with_capacity(arg.len());
+        for token in parse(arg)? {
+            match token {
+                Token::Text(text) => out.push_str(text),
+                Token::Placeholder(placeholder) => out.push_str(&self.resolve(placeholder, arg)?),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Substitutes the fixed placeholders in `arg`, the others are kept as written
+    ///
+    /// Doesn't allocate ports or temporary directories, for showing a command
+    /// outside of a run.
+    pub fn substitute_fixed(&self, arg: &str) -> Result<String, PlaceholderError> {
+        let mut out = String::with_capacity(arg.len());
+        for token in parse(arg)? {
+            match token {
+                Token::Text(text) => out.push_str(text),
+                Token::Placeholder(placeholder) => {
+                    let name = if placeholder.is_empty() {
+                        "image"
+                    } else {
+                        placeholder
+                    };
+                    match self.values.get(name) {
+                        Some(value) => out.push_str(value),
+                        None if is_known(placeholder) => {
+                            out.push('{');
+                            out.push_str(placeholder);
+                            out.push('}');
+                        }
+                        None => {
+                            return Err(PlaceholderError::Unknown {
+                                placeholder: placeholder.to_string(),
+                                arg: arg.to_string(),
+                            })
+                        }
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn resolve(&mut self, placeholder: &str, arg: &str) -> Result<String, PlaceholderError> {
+        let name = if placeholder.is_empty() {
+            "image"
+        } else {
+            placeholder
+        };
+        if let Some(value) = self.values.get(name) {
+            return Ok(value.clone());
+        }
+        if name == "tmp_dir" {
+            return self.tmp_dir().map(|dir| dir.display().to_string());
+        }
+        if let Some(port_name) = name.strip_prefix("port:").filter(|n| !n.is_empty()) {
+            return self.port(port_name).map(|port| port.to_string());
+        }
+        if let Some(var) = name.strip_prefix("env:").filter(|n| !n.is_empty()) {
+            return std::env::var(var).map_err(|_| PlaceholderError::MissingEnv(var.into()));
+        }
+        Err(PlaceholderError::Unknown {
+            placeholder: placeholder.to_string(),
+            arg: arg.to_string(),
+        })
+    }
+
+    fn port(&mut self, name: &str) -> Result<u16, PlaceholderError> {
+        if let Some(port) = self.ports.get(name) {
+            return Ok(*port);
+        }
+        let port = std::net::TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|listener| listener.local_addr())
+            .map_err(|e| PlaceholderError::Allocation {
+                placeholder: format!("port:{}", name),
+                error: e.to_string(),
+            })?
+            .port();
+        info!("Port `{}` is {}", name, port);
+        self.ports.insert(name.to_string(), port);
+        Ok(port)
+    }
+
+    fn tmp_dir(&mut self) -> Result<&Path, PlaceholderError> {
+        if self.tmp_dir.is_none() {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "glue_gun-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&dir).map_err(|e| PlaceholderError::Allocation {
+                placeholder: "tmp_dir".into(),
+                error: e.to_string(),
+            })?;
+            self.tmp_dir = Some(dir);
+        }
+        Ok(self.tmp_dir.as_deref().unwrap())
+    }
+}
+
+impl Drop for Placeholders {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.tmp_dir {
+            if let Err(e) = std::fs::remove_dir_all(dir) {
+                debug!("Failed to remove {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+impl<'a> Token<'a> {
+    fn placeholder(self) -> Option<&'a str> {
+        match self {
+            Token::Placeholder(name) => Some(name),
+            Token::Text(_) => None,
+        }
+    }
+}
+
+fn parse(arg: &str) -> Result<Vec<Token<'_>>, PlaceholderError> {
+    let mut tokens = Vec::new();
+    let mut rest = arg;
+    while let Some(pos) = rest.find(['{', '}']) {
+        if pos > 0 {
+            tokens.push(Token::Text(&rest[..pos]));
+        }
+        let brace = &rest[pos..pos + 1];
+        rest = &rest[pos + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            // Escaped `{{` or `}}`
+            tokens.push(Token::Text(brace));
+            rest = after;
+        } else if brace == "}" {
+            tokens.push(Token::Text(brace));
+        } else {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| PlaceholderError::Unclosed(arg.to_string()))?;
+            tokens.push(Token::Placeholder(&rest[..end]));
+            rest = &rest[end + 1..];
+        }
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes() {
+        let mut placeholders = Placeholders::new();
+        placeholders
+            .set("image", "/t/kernel.iso")
+            .set("crate_name", "kernel");
+        assert_eq!(
+            placeholders.substitute("format=raw,file={}").unwrap(),
+            "format=raw,file=/t/kernel.iso"
+        );
+        assert_eq!(
+            placeholders.substitute("{crate_name}-{{x}}").unwrap(),
+            "kernel-{x}"
+        );
+
+        let gdb = placeholders.substitute("tcp::{port:gdb}").unwrap();
+        assert_eq!(placeholders.substitute("tcp::{port:gdb}").unwrap(), gdb);
+
+        assert_eq!(
+            placeholders
+                .substitute_fixed("file={image},port={port:vnc}")
+                .unwrap(),
+            "file=/t/kernel.iso,port={port:vnc}"
+        );
+
+        std::env::set_var("PLACEHOLDER_TEST_VALUE", "42");
+        assert_eq!(
+            placeholders
+                .substitute("{env:PLACEHOLDER_TEST_VALUE}")
+                .unwrap(),
+            "42"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown() {
+        assert!(validate("-drive format=raw,file={image}").is_ok());
+        assert!(validate("{port:vnc} {env:HOME} {tmp_dir}").is_ok());
+        assert_eq!(
+            validate("{kernel}"),
+            Err(PlaceholderError::Unknown {
+                placeholder: "kernel".into(),
+                arg: "{kernel}".into()
+            })
+        );
+        assert!(matches!(
+            validate("{image"),
+            Err(PlaceholderError::Unclosed(_))
+        ));
+    }
+}
@@ -0,0 +1,393 @@
+//! Checks that an executable can be booted before it is glued into an ISO
+//!
+//! GRUB only reports problems with the kernel once QEMU runs, for example
+//! "no multiboot header found" or "entry point isn't in a segment". The
+//! checks here mirror what GRUB's multiboot2 loader and the generated
+//! bootloader expect of an ELF executable:
+//!
+//! - an x86 executable (`ET_EXEC`) without dynamic relocations
+//! - `PT_LOAD` segments that don't overlap in physical memory
+//! - an entry point inside a `PT_LOAD` segment
+//! - for the executable GRUB loads, a valid multiboot2 header within the
+//!   first 32 KiB of the file
+
+use object::{
+    elf,
+    read::elf::{Dyn, FileHeader, ProgramHeader},
+    Endianness, FileKind,
+};
+use std::ops::Range;
+use thiserror::Error;
+
+/// How far into the file GRUB searches for the multiboot2 header
+pub const MULTIBOOT2_SEARCH: usize = 32 * 1024;
+
+const MULTIBOOT2_MAGIC: u32 = 0xe852_50d6;
+/// The `i386` architecture of the multiboot2 header, used for x86_64 as well
+const MULTIBOOT2_ARCH_I386: u32 = 0;
+const MULTIBOOT2_TAG_END: u16 = 0;
+const MULTIBOOT2_TAG_ENTRY_ADDRESS: u16 = 3;
+/// Size of the packed relative relocations, missing from `object::elf`
+const DT_RELRSZ: u32 = 35;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PreflightError {
+    #[error("failed to parse the ELF file: {0}")]
+    Parse(String),
+
+    #[error("machine type {0} can't be booted through multiboot2, expected x86_64 or i386")]
+    Machine(u16),
+
+    #[error(
+        "ELF type {0} is not an executable (ET_EXEC), build the kernel with \
+         `\"position-independent-executables\": false`"
+    )]
+    NotExecutable(u16),
+
+    #[error("the executable requests the program interpreter `{0}`, link it statically")]
+    Interpreter(String),
+
+    #[error(
+        "the executable has {0} bytes of dynamic relocations nothing would apply, \
+         build it with `\"relocation-model\": \"static\"`"
+    )]
+    DynamicRelocations(u64),
+
+    #[error("the executable has no PT_LOAD segment")]
+    NoSegments,
+
+    #[error("PT_LOAD segment {index} has more bytes in the file ({filesz:#x}) than in memory ({memsz:#x})")]
+    FileSize {
+        index: usize,
+        filesz: u64,
+        memsz: u64,
+    },
+
+    #[error(
+        "PT_LOAD segments {first} ({first_range:#x?}) and {second} ({second_range:#x?}) \
+         overlap in physical memory"
+    )]
+    Overlap {
+        first: usize,
+        first_range: Range<u64>,
+        second: usize,
+        second_range: Range<u64>,
+    },
+
+    #[error("entry point {0:#x} is outside of every PT_LOAD segment")]
+    Entry(u64),
+
+    #[error(
+        "no multiboot2 header in the first 32 KiB of the file, GRUB would fail with \
+         \"no multiboot header found\""
+    )]
+    MissingMultiboot2,
+
+    #[error("multiboot2 header at offset {offset:#x}: {problem}")]
+    Multiboot2 { offset: usize, problem: String },
+}
+
+/// Checks the ELF executable `data`
+///
+/// `multiboot2` requires the multiboot2 header of an executable GRUB loads,
+/// a kernel embedded into a bootloader crate doesn't need one. Returns all
+/// problems found, an empty vector if the executable looks bootable.
+pub fn check(data: &[u8], multiboot2: bool) -> Vec<PreflightError> {
+    let result = match FileKind::parse(data) {
+        Ok(FileKind::Elf32) => check_elf::<elf::FileHeader32<Endianness>>(data, multiboot2),
+        Ok(FileKind::Elf64) => check_elf::<elf::FileHeader64<Endianness>>(data, multiboot2),
+        Ok(kind) => Err(PreflightError::Parse(format!("{:?} is not ELF", kind))),
+        Err(e) => Err(PreflightError::Parse(e.to_string())),
+    };
+    result.unwrap_or_else(|e| vec![e])
+}
+
+fn check_elf<Elf: FileHeader<Endian = Endianness>>(
+    data: &[u8],
+    multiboot2: bool,
+) -> Result<Vec<PreflightError>, PreflightError> {
+    let parse_error = |e: object::Error| PreflightError::Parse(e.to_string());
+    let header = Elf::parse(data).map_err(parse_error)?;
+    let endian = header.endian().map_err(parse_error)?;
+    let segments = header.program_headers(endian, data).map_err(parse_error)?;
+    let mut problems = Vec::new();
+
+    let machine = header.e_machine(endian);
+    if machine != elf::EM_X86_64 && machine != elf::EM_386 {
+        problems.push(PreflightError::Machine(machine));
+    }
+    let e_type = header.e_type(endian);
+    if e_type != elf::ET_EXEC {
+        problems.push(PreflightError::NotExecutable(e_type));
+    }
+
+    let mut loads = Vec::new();
+    for (index, segment) in segments.iter().enumerate() {
+        match segment.p_type(endian) {
+            elf::PT_INTERP => {
+                let interpreter = segment.interpreter(endian, data).ok().flatten();
+                problems.push(PreflightError::Interpreter(
+                    String::from_utf8_lossy(interpreter.unwrap_or_default()).into_owned(),
+                ));
+            }
+            elf::PT_DYNAMIC => {
+                let size = dynamic_relocations::<Elf>(segment, endian, data);
+                if size > 0 {
+                    problems.push(PreflightError::DynamicRelocations(size));
+                }
+            }
+            elf::PT_LOAD => {
+                let filesz: u64 = segment.p_filesz(endian).into();
+                let memsz: u64 = segment.p_memsz(endian).into();
+                if filesz > memsz {
+                    problems.push(PreflightError::FileSize {
+                        index,
+                        filesz,
+                        memsz,
+                    });
+                }
+                let paddr: u64 = segment.p_paddr(endian).into();
+                let vaddr: u64 = segment.p_vaddr(endian).into();
+                loads.push((
+                    index,
+                    paddr..paddr.saturating_add(memsz),
+                    vaddr..vaddr.saturating_add(memsz),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    if loads.is_empty() {
+        problems.push(PreflightError::NoSegments);
+    }
+    for (i, (first, first_range, _)) in loads.iter().enumerate() {
+        for (second, second_range, _) in &loads[i + 1..] {
+            if first_range.start < second_range.end && second_range.start < first_range.end {
+                problems.push(PreflightError::Overlap {
+                    first: *first,
+                    first_range: first_range.clone(),
+                    second: *second,
+                    second_range: second_range.clone(),
+                });
+            }
+        }
+    }
+
+    // Like GRUB, compare against the virtual addresses of the segments
+    let entry: u64 = header.e_entry(endian).into();
+    if !loads.is_empty() && !loads.iter().any(|(_, _, virt)| virt.contains(&entry)) {
+        problems.push(PreflightError::Entry(entry));
+    }
+
+    if multiboot2 {
+        let physical: Vec<Range<u64>> = loads.iter().map(|(_, phys, _)| phys.clone()).collect();
+        if let Err(e) = check_multiboot2(data, &physical) {
+            problems.push(e);
+        }
+    }
+    Ok(problems)
+}
+
+/// Returns the size of the relocation tables of a `PT_DYNAMIC` segment
+fn dynamic_relocations<Elf: FileHeader<Endian = Endianness>>(
+    segment: &Elf::ProgramHeader,
+    endian: Endianness,
+    data: &[u8],
+) -> u64 {
+    let Ok(Some(entries)) = segment.dynamic(endian, data) else {
+        return 0;
+    };
+    entries
+        .iter()
+        .filter(|entry| {
+            matches!(
+                entry.tag32(endian),
+                Some(elf::DT_RELASZ | elf::DT_RELSZ | DT_RELRSZ | elf::DT_PLTRELSZ)
+            )
+        })
+        .map(|entry| entry.d_val(endian).into())
+        .sum()
+}
+
+/// Validates the multiboot2 header and its tags
+///
+/// `segments` are the physical address ranges of the `PT_LOAD` segments.
+fn check_multiboot2(data: &[u8], segments: &[Range<u64>]) -> Result<(), PreflightError> {
+    let read = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let offset = (0..data.len().min(MULTIBOOT2_SEARCH))
+        .step_by(8)
+        .find(|offset| read(*offset) == Some(MULTIBOOT2_MAGIC))
+        .ok_or(PreflightError::MissingMultiboot2)?;
+    let problem = |problem: String| PreflightError::Multiboot2 { offset, problem };
+
+    let truncated = || problem("truncated by the end of the file".into());
+    let architecture = read(offset + 4).ok_or_else(truncated)?;
+    let length = read(offset + 8).ok_or_else(truncated)?;
+    let checksum = read(offset + 12).ok_or_else(truncated)?;
+    if MULTIBOOT2_MAGIC
+        .wrapping_add(architecture)
+        .wrapping_add(length)
+        .wrapping_add(checksum)
+        != 0
+    {
+        return Err(problem(format!(
+            "checksum {:#010x} doesn't match the magic, architecture and header length",
+            checksum
+        )));
+    }
+    if architecture != MULTIBOOT2_ARCH_I386 {
+        return Err(problem(format!(
+            "architecture {} is not i386 (0)",
+            architecture
+        )));
+    }
+    let end = offset + length as usize;
+    if (length as usize) < 16 + 8 || end > data.len() {
+        return Err(problem(format!("invalid header length {:#x}", length)));
+    }
+
+    let mut tag = offset + 16;
+    loop {
+        if tag + 8 > end {
+            return Err(problem("the tags are not terminated by an end tag".into()));
+        }
+        let ty = u16::from_le_bytes([data[tag], data[tag + 1]]);
+        let size = read(tag + 4).unwrap() as usize;
+        if size < 8 || tag + size > end {
+            return Err(problem(format!(
+                "tag {} at {:#x} has invalid size {:#x}",
+                ty, tag, size
+            )));
+        }
+        match ty {
+            MULTIBOOT2_TAG_END => return Ok(()),
+            MULTIBOOT2_TAG_ENTRY_ADDRESS if size >= 12 => {
+                let entry = u64::from(read(tag + 8).unwrap());
+                if !segments.iter().any(|segment| segment.contains(&entry)) {
+                    return Err(problem(format!(
+                        "entry address tag points to {:#x}, outside of every PT_LOAD segment",
+                        entry
+                    )));
+                }
+            }
+            _ => {}
+        }
+        // Tags are 8 byte aligned
+        tag += (size + 7) & !7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Multiboot2 header with an entry address tag
+    fn multiboot2(entry: u32) -> Vec<u8> {
+        let mut tags = Vec::new();
+        tags.extend(MULTIBOOT2_TAG_ENTRY_ADDRESS.to_le_bytes());
+        tags.extend(0u16.to_le_bytes());
+        tags.extend(12u32.to_le_bytes());
+        tags.extend(entry.to_le_bytes());
+        tags.extend([0; 4]);
+        tags.extend([0, 0, 0, 0, 8, 0, 0, 0]);
+        let length = 16 + tags.len() as u32;
+        let mut header = Vec::new();
+        for word in [
+            MULTIBOOT2_MAGIC,
+            0,
+            length,
+            0u32.wrapping_sub(MULTIBOOT2_MAGIC.wrapping_add(length)),
+        ] {
+            header.extend(word.to_le_bytes());
+        }
+        header.extend(tags);
+        header
+    }
+
+    /// A static ELF64 executable with the given `PT_LOAD` segments
+    /// (paddr, memsz) and the multiboot2 header at 0x1000
+    fn executable(entry: u64, segments: &[(u64, u64)], header: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 0x1000];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = elf::ELFCLASS64;
+        data[5] = elf::ELFDATA2LSB;
+        data[6] = elf::EV_CURRENT;
+        data[16..18].copy_from_slice(&elf::ET_EXEC.to_le_bytes());
+        data[18..20].copy_from_slice(&elf::EM_X86_64.to_le_bytes());
+        data[20..24].copy_from_slice(&1u32.to_le_bytes());
+        data[24..32].copy_from_slice(&entry.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[52..54].copy_from_slice(&64u16.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        data[58..60].copy_from_slice(&64u16.to_le_bytes());
+        for (i, (paddr, memsz)) in segments.iter().enumerate() {
+            let phdr = &mut data[64 + i * 56..][..56];
+            phdr[..4].copy_from_slice(&elf::PT_LOAD.to_le_bytes());
+            phdr[4..8].copy_from_slice(&(elf::PF_R | elf::PF_X).to_le_bytes());
+            phdr[16..24].copy_from_slice(&paddr.to_le_bytes());
+            phdr[24..32].copy_from_slice(&paddr.to_le_bytes());
+            phdr[40..48].copy_from_slice(&memsz.to_le_bytes());
+            phdr[48..56].copy_from_slice(&0x1000u64.to_le_bytes());
+        }
+        data.extend(header);
+        data
+    }
+
+    #[test]
+    fn accepts_bootable_executable() {
+        let segments = [(0x10_0000, 0x1000), (0x10_1000, 0x2000)];
+        let data = executable(0x10_0010, &segments, &multiboot2(0x10_0010));
+        assert_eq!(check(&data, true), vec![]);
+        let data = executable(0x10_0010, &segments, &[]);
+        assert_eq!(check(&data, false), vec![]);
+    }
+
+    #[test]
+    fn reports_problems() {
+        let data = executable(0x20_0000, &[(0x10_0000, 0x2000), (0x10_1000, 0x1000)], &[]);
+        assert_eq!(
+            check(&data, true),
+            vec![
+                PreflightError::Overlap {
+                    first: 0,
+                    first_range: 0x10_0000..0x10_2000,
+                    second: 1,
+                    second_range: 0x10_1000..0x10_2000,
+                },
+                PreflightError::Entry(0x20_0000),
+                PreflightError::MissingMultiboot2,
+            ]
+        );
+
+        let data = executable(0x10_0000, &[(0x10_0000, 0x1000)], &multiboot2(0x50_0000));
+        assert!(matches!(
+            &check(&data, true)[..],
+            [PreflightError::Multiboot2 { offset: 0x1000, problem }] if problem.contains("0x500000")
+        ));
+
+        let mut header = multiboot2(0x10_0000);
+        header[12] ^= 1;
+        let data = executable(0x10_0000, &[(0x10_0000, 0x1000)], &header);
+        assert!(matches!(
+            &check(&data, true)[..],
+            [PreflightError::Multiboot2 { problem, .. }] if problem.contains("checksum")
+        ));
+
+        // The unit tests are a position independent host executable
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let problems = check(&exe, false);
+        assert!(problems.contains(&PreflightError::NotExecutable(elf::ET_DYN)));
+        assert!(problems
+            .iter()
+            .any(|problem| matches!(problem, PreflightError::Interpreter(_))));
+        assert!(problems
+            .iter()
+            .any(|problem| matches!(problem, PreflightError::DynamicRelocations(_))));
+    }
+}
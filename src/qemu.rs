@@ -0,0 +1,280 @@
+//! Parses the `package.metadata.glue_gun.qemu` table and assembles the
+//! QEMU command line from it
+
+use anyhow::{anyhow, bail, Result};
+use toml::{value::Table, Value};
+
+/// The keys of the `qemu` table
+pub(crate) const KEYS: [&str; 10] = [
+    "binary",
+    "memory",
+    "smp",
+    "cpu",
+    "accel",
+    "machine",
+    "devices",
+    "drives",
+    "netdev",
+    "extra-args",
+];
+
+/// Accelerators accepted by `qemu.accel`
+const ACCELERATORS: [&str; 6] = ["kvm", "tcg", "hvf", "whpx", "xen", "nvmm"];
+
+/// Represents the `package.metadata.glue_gun.qemu` table
+///
+/// Every key maps to one QEMU option, so a single setting can be changed
+/// without copying the whole default `run-command`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QemuConfig {
+    /// The QEMU binary, defaults to `qemu-system-x86_64`
+    pub binary: Option<String>,
+    /// Guest memory passed to `-m`, for example `512M` or `2G`
+    pub memory: Option<String>,
+    /// CPU topology passed to `-smp`
+    pub smp: Option<String>,
+    /// CPU model passed to `-cpu`
+    pub cpu: Option<String>,
+    /// Accelerators in order of preference, each passed as `-accel`
+    pub accel: Vec<String>,
+    /// Machine type passed to `-machine`
+    pub machine: Option<String>,
+    /// Each entry is passed as `-device`
+    pub devices: Vec<String>,
+    /// Each entry is passed as `-drive`
+    pub drives: Vec<String>,
+    /// Each entry is passed as `-netdev`
+    pub netdev: Vec<String>,
+    /// Appended to the command line unchanged
+    pub extra_args: Vec<String>,
+}
+
+impl QemuConfig {
+    /// Parses the `qemu` table, errors name the offending key
+    pub fn parse(table: &Table) -> Result<Self> {
+        let mut qemu = QemuConfig::default();
+        qemu.apply(table)?;
+        Ok(qemu)
+    }
+
+    /// Applies the keys of a `qemu` table, overriding earlier values
+    pub fn apply(&mut self, table: &Table) -> Result<()> {
+        for (key, value) in table {
+            self.apply_key(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Applies a single key of a `qemu` table
+    pub fn apply_key(&mut self, key: &str, value: &Value) -> Result<()> {
+        let qemu = self;
+        let name = format!("qemu.{}", key);
+        match (key, value) {
+            ("binary", Value::String(binary)) => qemu.binary = Some(binary.clone()),
+            ("memory", Value::Integer(mib)) if *mib <= 0 => {
+                bail!("`{}` must be positive", name)
+            }
+            ("memory", Value::Integer(mib)) => qemu.memory = Some(format!("{}M", mib)),
+            ("memory", Value::String(memory)) => {
+                let digits = memory.trim_end_matches(['K', 'M', 'G', 'T']);
+                if digits.is_empty()
+                    || !digits.bytes().all(|b| b.is_ascii_digit())
+                    || memory.len() - digits.len() > 1
+                {
+                    bail!(
+                        "`{}` must be a size like `512M` or `2G`, got `{}`",
+                        name,
+                        memory
+                    );
+                }
+                qemu.memory = Some(memory.clone());
+            }
+            ("smp", Value::Integer(cpus)) if !(1..=1024).contains(cpus) => {
+                bail!("`{}` must be between 1 and 1024, got {}", name, cpus)
+            }
+            ("smp", Value::Integer(cpus)) => qemu.smp = Some(cpus.to_string()),
+            ("smp", Value::String(smp)) => qemu.smp = Some(smp.clone()),
+            ("cpu", Value::String(cpu)) => qemu.cpu = Some(cpu.clone()),
+            ("accel", Value::String(accel)) => {
+                qemu.accel = parse_accel(&name, accel.split(':').map(str::to_owned))?
+            }
+            ("accel", Value::Array(array)) => {
+                qemu.accel = parse_accel(&name, string_array(&name, array)?)?
+            }
+            ("machine", Value::String(machine)) => qemu.machine = Some(machine.clone()),
+            ("devices", Value::Array(array)) => qemu.devices = string_array(&name, array)?,
+            ("drives", Value::Array(array)) => qemu.drives = string_array(&name, array)?,
+            ("netdev", Value::String(netdev)) => qemu.netdev = vec![netdev.clone()],
+            ("netdev", Value::Array(array)) => qemu.netdev = string_array(&name, array)?,
+            ("extra-args", Value::Array(array)) => qemu.extra_args = string_array(&name, array)?,
+            (key, value) if KEYS.contains(&key) => {
+                bail!("`{}` has an invalid type `{}`", name, value.type_str())
+            }
+            (key, _) => return Err(crate::config::unknown_key(key, KEYS)),
+        }
+        Ok(())
+    }
+
+    /// Returns the keys that are set as `qemu` table
+    pub fn to_table(&self) -> Table {
+        let mut table = Table::new();
+        let strings =
+            |values: &[String]| Value::Array(values.iter().cloned().map(Value::String).collect());
+        let options = [
+            ("binary", &self.binary),
+            ("memory", &self.memory),
+            ("smp", &self.smp),
+            ("cpu", &self.cpu),
+            ("machine", &self.machine),
+        ];
+        for (key, value) in options {
+            if let Some(value) = value {
+                table.insert(key.into(), Value::String(value.clone()));
+            }
+        }
+        let lists = [
+            ("accel", &self.accel),
+            ("devices", &self.devices),
+            ("drives", &self.drives),
+            ("netdev", &self.netdev),
+            ("extra-args", &self.extra_args),
+        ];
+        for (key, values) in lists {
+            if !values.is_empty() {
+                table.insert(key.into(), strings(values));
+            }
+        }
+        table
+    }
+
+    /// Assembles the QEMU command line
+    ///
+    /// The disk image is referenced through the `{}` placeholder like in a
+    /// raw `run-command`. In debug mode QEMU waits for a gdb connection.
+    pub fn command(&self, is_debug: bool) -> Vec<String> {
+        let mut cmd = vec![
+            self.binary
+                .clone()
+                .unwrap_or_else(|| "qemu-system-x86_64".into()),
+            "-cdrom".into(),
+            "{}".into(),
+            "-serial".into(),
+            "stdio".into(),
+        ];
+        let mut push = |flag: &str, value: &str| {
+            cmd.push(flag.into());
+            cmd.push(value.into());
+        };
+        if let Some(memory) = &self.memory {
+            push("-m", memory);
+        }
+        if let Some(smp) = &self.smp {
+            push("-smp", smp);
+        }
+        if let Some(cpu) = &self.cpu {
+            push("-cpu", cpu);
+        }
+        for accel in &self.accel {
+            push("-accel", accel);
+        }
+        if let Some(machine) = &self.machine {
+            push("-machine", machine);
+        }
+        for device in &self.devices {
+            push("-device", device);
+        }
+        for drive in &self.drives {
+            push("-drive", drive);
+        }
+        for netdev in &self.netdev {
+            push("-netdev", netdev);
+        }
+        cmd.extend(self.extra_args.iter().cloned());
+        if is_debug {
+            cmd.push("-s".into());
+            cmd.push("-S".into());
+        }
+        cmd
+    }
+}
+
+fn parse_accel<I>(name: &str, accels: I) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = String>,
+{
+    accels
+        .into_iter()
+        .map(|accel| {
+            let backend = accel.split(',').next().unwrap_or("");
+            if ACCELERATORS.contains(&backend) {
+                Ok(accel)
+            } else {
+                Err(anyhow!(
+                    "`{}` contains unknown accelerator `{}`, expected one of {}",
+                    name,
+                    backend,
+                    ACCELERATORS.join(", ")
+                ))
+            }
+        })
+        .collect()
+}
+
+fn string_array(name: &str, array: &[Value]) -> Result<Vec<String>> {
+    array
+        .iter()
+        .map(|value| match value {
+            Value::String(s) => Ok(s.clone()),
+            _ => Err(anyhow!("`{}` must be a list of strings", name)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<QemuConfig> {
+        QemuConfig::parse(toml.parse::<Value>().unwrap().as_table().unwrap())
+    }
+
+    #[test]
+    fn assembles_command() {
+        let qemu = parse(
+            r#"
+            memory = "2G"
+            smp = 4
+            accel = "kvm:tcg"
+            devices = ["isa-debug-exit,iobase=0xf4,iosize=0x04"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            qemu.command(false)[5..],
+            [
+                "-m",
+                "2G",
+                "-smp",
+                "4",
+                "-accel",
+                "kvm",
+                "-accel",
+                "tcg",
+                "-device",
+                "isa-debug-exit,iobase=0xf4,iosize=0x04"
+            ]
+        );
+    }
+
+    #[test]
+    fn names_bad_key() {
+        let err = parse("memory = \"lots\"").unwrap_err().to_string();
+        assert!(err.contains("qemu.memory"), "{}", err);
+        let err = parse("accel = [\"kvm\", \"fast\"]")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("qemu.accel"), "{}", err);
+        let err = parse("smp = \"4\"\nmemroy = 1").unwrap_err().to_string();
+        assert!(err.contains("memroy"), "{}", err);
+    }
+}
@@ -0,0 +1,401 @@
+//! A small async client for the QEMU Machine Protocol (QMP)
+//!
+//! QEMU is started with `-qmp unix:<socket>,server=on,wait=off` and glue_gun
+//! connects to that socket to control the running emulator.
+
+use json::{object, JsonValue};
+use log::*;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+};
+
+/// Returns the QEMU arguments for exposing a QMP server on `socket`
+pub fn qemu_args(socket: &Path) -> Vec<String> {
+    vec![
+        "-qmp".into(),
+        format!("unix:{},server=on,wait=off", socket.display()),
+    ]
+}
+
+/// A connection to a QMP server with negotiated capabilities
+pub struct QmpClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    events: Vec<JsonValue>,
+}
+
+impl QmpClient {
+    /// Connects to the QMP server and leaves capabilities negotiation mode
+    pub async fn connect(socket: &Path) -> Result<Self, QmpError> {
+        let stream = UnixStream::connect(socket).await?;
+        let (reader, writer) = stream.into_split();
+        let mut client = Self {
+            reader: BufReader::new(reader),
+            writer,
+            events: Vec::new(),
+        };
+
+        let greeting = client.read_message().await?;
+        if !greeting.has_key("QMP") {
+            return Err(QmpError::Protocol(format!(
+                "Expected QMP greeting, got: {}",
+                greeting.dump()
+            )));
+        }
+        debug!("QMP greeting: {}", greeting.dump());
+        client.execute("qmp_capabilities", None).await?;
+        Ok(client)
+    }
+
+    /// Connects to the QMP server, retrying until QEMU created the socket
+    pub async fn connect_with_retry(socket: &Path, timeout: Duration) -> Result<Self, QmpError> {
+        let start = tokio::time::Instant::now();
+        loop {
+            match Self::connect(socket).await {
+                Err(QmpError::Io(e)) if start.elapsed() < timeout => {
+                    trace!("Waiting for QMP socket {}: {}", socket.display(), e);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Executes a QMP command and returns the content of its `return` value
+    ///
+    /// Asynchronous events received while waiting are stored and can be
+    /// retrieved with [`QmpClient::take_events`].
+    pub async fn execute(
+        &mut self,
+        command: &str,
+        arguments: Option<JsonValue>,
+    ) -> Result<JsonValue, QmpError> {
+        let mut request = object! { "execute": command };
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        trace!("QMP request: {}", request.dump());
+        self.writer
+            .write_all(format!("{}\n", request.dump()).as_bytes())
+            .await?;
+
+        loop {
+            let mut response = self.read_message().await?;
+            if response.has_key("return") {
+                return Ok(response["return"].take());
+            }
+            if response.has_key("error") {
+                return Err(QmpError::Command {
+                    class: response["error"]["class"].to_string(),
+                    desc: response["error"]["desc"].to_string(),
+                });
+            }
+            if response.has_key("event") {
+                self.events.push(response);
+                continue;
+            }
+            return Err(QmpError::Protocol(format!(
+                "Unexpected QMP message: {}",
+                response.dump()
+            )));
+        }
+    }
+
+    /// Executes a human monitor (HMP) command and returns its output
+    pub async fn human_monitor_command(&mut self, command_line: &str) -> Result<String, QmpError> {
+        let output = self
+            .execute(
+                "human-monitor-command",
+                Some(object! { "command-line": command_line }),
+            )
+            .await?;
+        output
+            .as_str()
+            .map(str::to_owned)
+            .ok_or_else(|| QmpError::Protocol(format!("Expected string, got: {}", output.dump())))
+    }
+
+    /// Returns the register dump of all CPUs
+    pub async fn register_dump(&mut self) -> Result<String, QmpError> {
+        self.human_monitor_command("info registers").await
+    }
+
+    /// Saves a PPM screenshot of the emulator display to `path`
+    pub async fn screendump(&mut self, path: &Path) -> Result<(), QmpError> {
+        let filename = path.display().to_string();
+        self.execute("screendump", Some(object! { "filename": filename }))
+            .await?;
+        Ok(())
+    }
+
+    /// Pauses the emulation
+    pub async fn stop(&mut self) -> Result<(), QmpError> {
+        self.execute("stop", None).await.map(|_| ())
+    }
+
+    /// Resumes the emulation
+    pub async fn cont(&mut self) -> Result<(), QmpError> {
+        self.execute("cont", None).await.map(|_| ())
+    }
+
+    /// Returns the run state of the emulator, for example `running` or `paused`
+    pub async fn status(&mut self) -> Result<String, QmpError> {
+        let status = self.execute("query-status", None).await?;
+        Ok(status["status"].to_string())
+    }
+
+    /// Creates a snapshot of the whole VM with the given name
+    ///
+    /// Requires a writable qcow2 drive attached to the VM.
+    pub async fn snapshot_save(&mut self, name: &str) -> Result<String, QmpError> {
+        self.human_monitor_command(&format!("savevm {}", name))
+            .await
+    }
+
+    /// Restores the snapshot with the given name
+    pub async fn snapshot_load(&mut self, name: &str) -> Result<String, QmpError> {
+        self.human_monitor_command(&format!("loadvm {}", name))
+            .await
+    }
+
+    /// Asks the guest to power down via ACPI
+    pub async fn system_powerdown(&mut self) -> Result<(), QmpError> {
+        self.execute("system_powerdown", None).await.map(|_| ())
+    }
+
+    /// Terminates QEMU
+    ///
+    /// QEMU may close the connection before it replies, which counts as success.
+    pub async fn quit(&mut self) -> Result<(), QmpError> {
+        match self.execute("quit", None).await {
+            Ok(_) | Err(QmpError::Disconnected) => Ok(()),
+            Err(QmpError::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+                ) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns and clears the events received so far
+    pub fn take_events(&mut self) -> Vec<JsonValue> {
+        std::mem::take(&mut self.events)
+    }
+
+    async fn read_message(&mut self) -> Result<JsonValue, QmpError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(QmpError::Disconnected);
+        }
+        trace!("QMP response: {}", line.trim_end());
+        json::parse(&line).map_err(|e| QmpError::Protocol(format!("Invalid JSON: {}", e)))
+    }
+}
+
+/// Talking to the QMP server failed
+#[derive(Debug, Error)]
+pub enum QmpError {
+    /// An I/O error occured on the socket
+    #[error("QMP I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// QEMU closed the connection
+    #[error("QMP connection closed by QEMU")]
+    Disconnected,
+
+    /// QEMU sent something unexpected
+    #[error("QMP protocol error: {0}")]
+    Protocol(String),
+
+    /// The socket path doesn't fit into a Unix socket address
+    #[error(
+        "QMP socket path {} is longer than {MAX_SOCKET_PATH} bytes, point XDG_RUNTIME_DIR or TMPDIR to a shorter directory",
+        .0.display()
+    )]
+    SocketPath(PathBuf),
+
+    /// QEMU returned an error for the command
+    #[error("QMP command failed ({class}): {desc}")]
+    Command {
+        /// The error class, for example `GenericError`
+        class: String,
+        /// The human readable error description
+        desc: String,
+    },
+}
+
+/// The longest path a Unix socket address holds, `sun_path` without the NUL
+const MAX_SOCKET_PATH: usize = 107;
+
+/// Returns the QMP socket path used for the given disk image
+///
+/// Target directories are often too deep for a Unix socket path, the socket
+/// is created in `$XDG_RUNTIME_DIR` or the temporary directory instead,
+/// named after a hash of the image path.
+pub fn socket_path(image_path: &Path) -> Result<PathBuf, QmpError> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_dir())
+        .unwrap_or_else(std::env::temp_dir);
+    socket_path_in(&dir, image_path)
+}
+
+fn socket_path_in(dir: &Path, image_path: &Path) -> Result<PathBuf, QmpError> {
+    let hash = crate::staging::fnv1a(image_path.to_string_lossy().as_bytes());
+    let path = dir.join(format!("glue_gun-{:016x}.qmp", hash));
+    if path.as_os_str().len() > MAX_SOCKET_PATH {
+        return Err(QmpError::SocketPath(path));
+    }
+    Ok(path)
+}
+
+/// Sends a single command to a running QEMU session and prints the result
+///
+/// Implements `glue_gun qmp`.
+pub async fn glue_gun_qmp(socket: &Path, command: &[String]) -> Result<(), QmpError> {
+    let mut qmp = QmpClient::connect(socket).await?;
+    let args: Vec<&str> = command.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["pause"] => qmp.stop().await?,
+        ["resume"] => qmp.cont().await?,
+        ["status"] => println!("{}", qmp.status().await?),
+        ["registers"] => print!("{}", qmp.register_dump().await?),
+        ["screendump", path] => qmp.screendump(Path::new(path)).await?,
+        ["snapshot-save", name] => print!("{}", qmp.snapshot_save(name).await?),
+        ["snapshot-load", name] => print!("{}", qmp.snapshot_load(name).await?),
+        ["powerdown"] => qmp.system_powerdown().await?,
+        ["quit"] => qmp.quit().await?,
+        _ => {
+            return Err(QmpError::Protocol(format!(
+                "Unknown command `{}`",
+                command.join(" ")
+            )))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    #[test]
+    fn places_sockets() {
+        let image = Path::new("/home/runner/work/kernel/kernel/target/x86_64-none/debug/deps/kernel-0123456789abcdef.iso");
+        let socket = socket_path_in(Path::new("/run/user/1000"), image).unwrap();
+        assert!(socket.starts_with("/run/user/1000"));
+        assert_eq!(
+            socket,
+            socket_path_in(Path::new("/run/user/1000"), image).unwrap()
+        );
+        assert!(matches!(
+            socket_path_in(&Path::new("/tmp").join("x".repeat(100)), image),
+            Err(QmpError::SocketPath(_))
+        ));
+    }
+
+    /// Accepts a single client and replies to each request with `replies` in order
+    async fn mock_server(
+        socket: &Path,
+        replies: Vec<&'static str>,
+    ) -> tokio::task::JoinHandle<Vec<String>> {
+        let _ = std::fs::remove_file(socket);
+        let listener = UnixListener::bind(socket).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            writer
+                .write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\n")
+                .await
+                .unwrap();
+            let mut requests = Vec::new();
+            for reply in replies {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                requests.push(line.trim_end().to_string());
+                writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+            requests
+        })
+    }
+
+    fn socket(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("glue_gun-{}-{}.qmp", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn executes_commands() {
+        let socket = socket("execute");
+        let server = mock_server(
+            &socket,
+            vec![
+                "{\"return\": {}}\n",
+                "{\"event\": \"STOP\", \"timestamp\": {}}\n{\"return\": {}}\n",
+                "{\"return\": {\"running\": false, \"status\": \"paused\"}}\n",
+                "{\"return\": \"RAX=0000000000000000 RBX=0000000000000001\\r\\n\"}\n",
+            ],
+        )
+        .await;
+
+        let mut client = QmpClient::connect_with_retry(&socket, Duration::from_secs(5))
+            .await
+            .unwrap();
+        client.stop().await.unwrap();
+        assert_eq!(client.take_events().len(), 1);
+        assert_eq!(client.status().await.unwrap(), "paused");
+        assert!(client.register_dump().await.unwrap().starts_with("RAX="));
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0], r#"{"execute":"qmp_capabilities"}"#);
+        assert_eq!(requests[1], r#"{"execute":"stop"}"#);
+        assert_eq!(
+            requests[3],
+            r#"{"execute":"human-monitor-command","arguments":{"command-line":"info registers"}}"#
+        );
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn reports_command_errors() {
+        let socket = socket("error");
+        let _server = mock_server(
+            &socket,
+            vec![
+                "{\"return\": {}}\n",
+                "{\"error\": {\"class\": \"GenericError\", \"desc\": \"no such file\"}}\n",
+            ],
+        )
+        .await;
+
+        let mut client = QmpClient::connect(&socket).await.unwrap();
+        match client
+            .screendump(Path::new("/nonexistent/screen.ppm"))
+            .await
+        {
+            Err(QmpError::Command { class, desc }) => {
+                assert_eq!(class, "GenericError");
+                assert_eq!(desc, "no such file");
+            }
+            other => panic!("Expected command error, got {:?}", other.map(|_| ())),
+        }
+        // The server hung up after its last reply
+        assert!(matches!(client.quit().await, Ok(())));
+        let _ = std::fs::remove_file(&socket);
+    }
+}
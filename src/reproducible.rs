@@ -0,0 +1,237 @@
+//! Reproducible ISO images, for `reproducible = true` and `glue_gun verify-reproducible`
+//!
+//! The same merged executable gives a bit-identical ISO. Every date written
+//! to the image is taken from `SOURCE_DATE_EPOCH`, 1970-01-01 if it isn't
+//! set. The files in `isofiles` get that date as mtime, and fixed permissions
+//! so the umask doesn't end up in the Rock Ridge attributes.
+//! `grub-mkrescue` passes `SOURCE_DATE_EPOCH` on to xorriso and mformat,
+//! which derive the volume dates, the volume UUID and the FAT serial of the
+//! EFI image from it. The modules GRUB copies into the image are written at
+//! build time, so all file dates are overridden as well. xorriso stores
+//! directory entries sorted by name, the tree is normalized in the same order.
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use std::{fs, io, path::Path, process::Command};
+
+use crate::{build::Artifact, config::Config, CliOptions, Manifests};
+
+/// ISO 9660 sectors, used to locate a difference in the image
+const SECTOR: u64 = 2048;
+
+/// Returns the timestamp of a reproducible build
+///
+/// Reads `SOURCE_DATE_EPOCH`, defaults to 0.
+pub fn source_date_epoch() -> Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.trim().parse().map_err(|_| {
+            anyhow!(
+                "SOURCE_DATE_EPOCH must be a number of seconds, got `{}`",
+                epoch
+            )
+        }),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Formats a timestamp as `YYYYMMDDhhmmsscc` in UTC, the format of
+/// `xorriso -as mkisofs --modification-date`
+pub fn mkisofs_date(epoch: u64) -> String {
+    let days = (epoch / 86400) as i64;
+    let seconds = epoch % 86400;
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}00",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Sets the mtime of every file and directory below `dir`, including
+/// `dir`, to `epoch` and resets their permissions
+pub fn normalize_tree(dir: &Path, epoch: u64) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            normalize_tree(&path, epoch)?;
+        } else {
+            normalize(&path, 0o644, epoch)?;
+        }
+    }
+    // After the children, writing to a directory changes its mtime
+    normalize(dir, 0o755, epoch)
+}
+
+fn normalize(path: &Path, mode: u32, epoch: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let time = filetime::FileTime::from_unix_time(epoch as i64, 0);
+    filetime::set_file_times(path, time, time)
+}
+
+/// Adds the environment and `xorriso` options making `grub-mkrescue` deterministic
+pub fn grub_mkrescue_args(cmd: &mut Command, epoch: u64) {
+    let date = mkisofs_date(epoch);
+    cmd.env("SOURCE_DATE_EPOCH", epoch.to_string());
+    // Everything after `--` is passed to `xorriso -as mkisofs`
+    cmd.arg("--");
+    cmd.arg(format!("--modification-date={}", date));
+    cmd.arg("--set_all_file_dates").arg(&date);
+}
+
+/// Where two images differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Difference {
+    /// The offset of the first differing byte
+    pub offset: u64,
+    /// The number of differing bytes, bytes only one image has included
+    pub bytes: u64,
+}
+
+/// Compares two images byte by byte, `None` if they are identical
+pub fn compare(first: &[u8], second: &[u8]) -> Option<Difference> {
+    let mut offset = None;
+    let mut bytes = first.len().abs_diff(second.len()) as u64;
+    for (i, (a, b)) in first.iter().zip(second).enumerate() {
+        if a != b {
+            offset.get_or_insert(i as u64);
+            bytes += 1;
+        }
+    }
+    match offset {
+        Some(offset) => Some(Difference { offset, bytes }),
+        None if bytes > 0 => Some(Difference {
+            offset: first.len().min(second.len()) as u64,
+            bytes,
+        }),
+        None => None,
+    }
+}
+
+/// Builds the ISO twice in reproducible mode and compares the images
+///
+/// The kernel is only built once by cargo, this checks what glue_gun adds.
+/// If the images differ, the first one is kept next to the second for
+/// tools like `diffoscope`. Returns whether the images are identical.
+pub fn glue_gun_verify_reproducible(
+    kernel: &Artifact,
+    manifests: &Manifests,
+    config: &Config,
+    cli_options: &CliOptions,
+) -> Result<bool> {
+    let mut config = config.clone();
+    config.reproducible = true;
+    config
+        .sources
+        .insert("reproducible".into(), "glue_gun verify-reproducible".into());
+
+    let first = crate::build::glue_gun_build(kernel, manifests, &config, cli_options);
+    let kept = first.staging_dir.join("first.iso");
+    fs::copy(&first.iso_img, &kept)
+        .with_context(|| format!("Failed to copy {}", first.iso_img.display()))?;
+    info!("Building {} a second time", first.iso_img.display());
+    let second = crate::build::glue_gun_build(kernel, manifests, &config, cli_options);
+
+    let read =
+        |path: &Path| fs::read(path).with_context(|| format!("Failed to read {}", path.display()));
+    match compare(&read(&kept)?, &read(&second.iso_img)?) {
+        None => {
+            info!("{} is reproducible", second.iso_img.display());
+            fs::remove_file(&kept)
+                .with_context(|| format!("Failed to remove {}", kept.display()))?;
+            Ok(true)
+        }
+        Some(difference) => {
+            error!(
+                "{} differs from the first build in {} bytes, starting at offset {:#x} (sector {})",
+                second.iso_img.display(),
+                difference.bytes,
+                difference.offset,
+                difference.offset / SECTOR
+            );
+            error!(
+                "The first image is kept at {}, compare them with `diffoscope {} {}`",
+                kept.display(),
+                kept.display(),
+                second.iso_img.display()
+            );
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(mkisofs_date(0), "1970010100000000");
+        assert_eq!(mkisofs_date(951782400), "2000022900000000");
+        assert_eq!(mkisofs_date(1700000000), "2023111422132000");
+        assert_eq!(mkisofs_date(4102444799), "2099123123595900");
+    }
+
+    #[test]
+    fn normalizes_trees() {
+        let dir = std::env::temp_dir().join(format!("glue_gun-isofiles-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("boot/grub")).unwrap();
+        fs::write(dir.join("boot/grub/grub.cfg"), "set timeout=0").unwrap();
+        fs::write(dir.join("boot/kernel.elf"), "kernel").unwrap();
+
+        normalize_tree(&dir, 1700000000).unwrap();
+        let expected = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+        for path in [
+            "",
+            "boot",
+            "boot/grub",
+            "boot/grub/grub.cfg",
+            "boot/kernel.elf",
+        ] {
+            let metadata = fs::metadata(dir.join(path)).unwrap();
+            assert_eq!(metadata.modified().unwrap(), expected, "{}", path);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compares_images() {
+        assert_eq!(compare(b"same", b"same"), None);
+        assert_eq!(
+            compare(b"abcdef", b"abXdeY"),
+            Some(Difference {
+                offset: 2,
+                bytes: 2
+            })
+        );
+        assert_eq!(
+            compare(b"abc", b"abcde"),
+            Some(Difference {
+                offset: 3,
+                bytes: 2
+            })
+        );
+    }
+}
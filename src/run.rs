@@ -1,102 +1,407 @@
 //! Provides a function for running a disk image in QEMU.
 
-use crate::config::Config;
-use std::{io, path::Path, process, time::Duration};
+use crate::build::BuildMetadata;
+use crate::config::{Config, Display};
+use crate::diagnostics::{self, DiagnosticFiles};
+use crate::placeholder::{PlaceholderError, Placeholders};
+use crate::qmp::{self, QmpClient};
+use crate::screenshot::{self, Screenshots};
+use crate::symbolize::{self, Symbolizer};
+use log::*;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use thiserror::Error;
-use wait_timeout::ChildExt;
+use tokio::{
+    io::BufReader,
+    process::{Child, Command},
+    task::JoinHandle,
+};
+
+/// How long QEMU gets to exit after a QMP `quit` before it is killed
+const QUIT_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Run the given disk image in QEMU.
 ///
 /// Automatically takes into account the runner arguments and the run/test
-/// commands defined in the given `Config`. Since test executables are treated
-/// differently (run with a timeout and match exit status), the caller needs to
-/// specify whether the given disk image is a test or not.
-pub fn glue_gun_run(
-    config: Config,
-    image_path: &Path,
-    is_test: bool,
-    is_debug: bool,
-) -> Result<i32, RunError> {
-    let mut run_command: Vec<_> = if is_debug {
-        config
-            .debug_run_command
-            .iter()
-            .map(|arg| arg.replace("{}", &format!("{}", image_path.display())))
-            .collect()
+/// commands defined in the config of the build. Test executables are treated
+/// differently, they are run with a timeout and their exit status is matched.
+///
+/// Placeholders in the run command are substituted with the artifacts of
+/// the build, see [`crate::placeholder`].
+///
+/// Addresses found in the serial output are resolved against the symbol files
+/// and printed right after the line they appeared in. In diagnostic mode a
+/// crash summary is printed after an abnormal exit or a test timeout.
+///
+/// A failing or hanging test is reported as an error which includes the path
+/// to a screenshot of the emulator display if one could be taken.
+pub async fn glue_gun_run(artifacts: &BuildMetadata, is_debug: bool) -> Result<i32, RunError> {
+    let config = &artifacts.config;
+    let image_path = artifacts.iso_img.as_path();
+    let is_test = artifacts.is_test;
+    let mut symbol_files = vec![artifacts.kernel_sym.as_path()];
+    symbol_files.extend(artifacts.bootloader_sym.as_deref());
+    let symbolizer = create_symbolizer(config, &symbol_files);
+
+    let mut run_command = run_command(config, is_test, is_debug);
+
+    let display = if is_test {
+        Some(config.test_display)
     } else {
-        config
-            .run_command
-            .iter()
-            .map(|arg| arg.replace("{}", &format!("{}", image_path.display())))
-            .collect()
+        config.display
     };
-    if is_test {
-        if let Some(args) = config.test_args {
-            run_command.extend(args);
+    if let Some(display) = display {
+        if selects_display(&run_command) {
+            debug!(
+                "Run command already selects a display, ignoring `{}`",
+                display
+            );
+        } else {
+            run_command.extend(display_args(display));
         }
-    } else if let Some(args) = config.run_args {
-        run_command.extend(args);
     }
 
-    log::info!("Running: `{}`", run_command.join(" "));
+    let qmp_socket = config
+        .qmp
+        .then(|| qmp::socket_path(image_path))
+        .transpose()?;
+    if let Some(socket) = &qmp_socket {
+        let _ = std::fs::remove_file(socket);
+        run_command.extend(qmp::qemu_args(socket));
+        info!("QMP socket: {}", socket.display());
+    }
+
+    let screenshots = (is_test && config.test_screenshots && qmp_socket.is_some())
+        .then(|| Screenshots::new(image_path));
+    if let Some(screenshots) = &screenshots {
+        screenshots.reset();
+    }
+
+    let diagnostic_files = config
+        .diagnostics
+        .then(|| DiagnosticFiles::new(image_path, config.diagnostics_log.as_deref()));
+    if let Some(files) = &diagnostic_files {
+        files.reset();
+        run_command.extend(files.qemu_args(&config.diagnostics_log_items));
+    }
+
+    let mut placeholders = placeholders(artifacts);
+    let run_command = run_command
+        .iter()
+        .map(|arg| placeholders.substitute(arg))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    info!("Running: `{}`", run_command.join(" "));
 
-    let mut command = process::Command::new(&run_command[0]);
+    let serial_symbolizer = symbolizer.clone().filter(|_| config.symbolize_backtraces);
+    let mut command = Command::new(&run_command[0]);
     command.args(&run_command[1..]);
+    command.kill_on_drop(true);
+    if serial_symbolizer.is_some() {
+        command.stdout(Stdio::piped());
+    }
 
-    let exit_code = if is_test {
-        let mut child = command.spawn().map_err(|error| RunError::Io {
-            context: IoErrorContext::QemuTestCommand {
+    let mut child = command.spawn().map_err(|error| RunError::Io {
+        context: if is_test {
+            IoErrorContext::QemuTestCommand {
+                command: format!("{:?}", command),
+            }
+        } else {
+            IoErrorContext::QemuRunCommand {
                 command: format!("{:?}", command),
-            },
-            error,
-        })?;
+            }
+        },
+        error,
+    })?;
+    let forwarder = forward_serial(&mut child, serial_symbolizer);
+    let poller = screenshots
+        .as_ref()
+        .zip(qmp_socket.as_ref())
+        .map(|(s, socket)| {
+            s.poll(
+                socket.clone(),
+                Duration::from_millis(config.test_screenshot_interval.into()),
+            )
+        });
+
+    let exit_code = if is_test {
         let timeout = Duration::from_secs(config.test_timeout.into());
-        match child
-            .wait_timeout(timeout)
-            .map_err(context(IoErrorContext::WaitWithTimeout))?
-        {
+        let wait_result = tokio::time::timeout(timeout, child.wait()).await;
+        // The QMP server only accepts one client at a time
+        stop_poller(poller).await;
+        let exit_status = match wait_result {
+            Ok(status) => status.map_err(context(IoErrorContext::WaitForQemu))?,
+            Err(_) => {
+                let session = TimeoutSession {
+                    qmp_socket: qmp_socket.as_deref(),
+                    screenshot: screenshots.as_ref().map(|s| s.timeout.as_path()),
+                    diagnostic_files: diagnostic_files.as_ref(),
+                    symbolizer: symbolizer.as_deref(),
+                };
+                let screenshot = session.shutdown(&mut child).await?;
+                join_forwarder(forwarder).await;
+                return Err(RunError::TestTimedOut { screenshot });
+            }
+        };
+        join_forwarder(forwarder).await;
+        test_exit_code(config, exit_status)?
+    } else {
+        let status = child
+            .wait()
+            .await
+            .map_err(context(IoErrorContext::WaitForQemu))?;
+        join_forwarder(forwarder).await;
+        status.code().unwrap_or(1)
+    };
+
+    if let Some(files) = &diagnostic_files {
+        diagnostics::report_crash(files, symbolizer.as_deref(), exit_code != 0);
+    }
+
+    if is_test && exit_code != 0 {
+        let screenshot = screenshots.and_then(|s| s.save_failure());
+        return Err(RunError::TestFailed {
+            exit_code,
+            screenshot,
+        });
+    }
+
+    Ok(exit_code)
+}
+
+/// Returns the run or debug run command with the arguments of the executable
+/// kind, before placeholders are substituted
+///
+/// The display, QMP and diagnostic arguments depend on the run, they are
+/// added by [`glue_gun_run`].
+pub fn run_command(config: &Config, is_test: bool, is_debug: bool) -> Vec<String> {
+    let mut run_command = if is_debug {
+        config.debug_run_command.clone()
+    } else {
+        config.run_command.clone()
+    };
+    if is_test {
+        if let Some(args) = &config.test_args {
+            run_command.extend(args.iter().cloned());
+        }
+        // A `-no-reboot` of the user is kept even with `test-no-reboot = false`
+        if config.test_no_reboot && !run_command.iter().any(|arg| arg == "-no-reboot") {
+            run_command.push("-no-reboot".into());
+        }
+    } else if let Some(args) = &config.run_args {
+        run_command.extend(args.iter().cloned());
+    }
+
+    run_command
+}
+
+/// Returns the placeholders of the artifacts of a build
+pub fn placeholders(artifacts: &BuildMetadata) -> Placeholders {
+    let mut placeholders = Placeholders::new();
+    placeholders
+        .set_path("image", &artifacts.iso_img)
+        .set_path("kernel_elf", &artifacts.kernel_elf)
+        .set_path("kernel_sym", &artifacts.kernel_sym)
+        .set_path(
+            "bootloader_sym",
+            artifacts
+                .bootloader_sym
+                .as_deref()
+                .unwrap_or(&artifacts.kernel_sym),
+        )
+        .set_path("target_dir", &artifacts.target_dir)
+        .set("crate_name", artifacts.crate_name.as_str());
+    placeholders
+}
+
+/// Returns true if the run command configures the display by itself
+fn selects_display(run_command: &[String]) -> bool {
+    run_command.iter().any(|arg| {
+        matches!(
+            arg.as_str(),
+            "-display" | "-nographic" | "-vnc" | "-curses" | "-sdl"
+        )
+    })
+}
+
+/// Returns the QEMU arguments selecting the given display backend
+///
+/// VNC binds to the first free display on localhost and prints the address.
+fn display_args(display: Display) -> Vec<String> {
+    let backend = match display {
+        Display::Vnc => match free_vnc_display() {
+            Some(n) => {
+                info!("VNC server listening on 127.0.0.1:{}", 5900 + n);
+                format!("vnc=127.0.0.1:{}", n)
+            }
             None => {
-                child.kill().map_err(context(IoErrorContext::KillQemu))?;
-                child.wait().map_err(context(IoErrorContext::WaitForQemu))?;
-                return Err(RunError::TestTimedOut);
+                warn!("No free VNC port between 5900 and 5999, disabling display");
+                "none".to_string()
             }
-            Some(exit_status) => {
-                #[cfg(unix)]
-                {
-                    if exit_status.code().is_none() {
-                        use std::os::unix::process::ExitStatusExt;
-                        if let Some(signal) = exit_status.signal() {
-                            eprintln!("QEMU process was terminated by signal {}", signal);
+        },
+        display => display.to_string(),
+    };
+    vec!["-display".into(), backend]
+}
+
+fn free_vnc_display() -> Option<u16> {
+    (0..100).find(|n| std::net::TcpListener::bind(("127.0.0.1", 5900 + n)).is_ok())
+}
+
+/// Maps the exit status of a test run to the exit code of glue_gun
+fn test_exit_code(config: &Config, exit_status: ExitStatus) -> Result<i32, RunError> {
+    #[cfg(unix)]
+    {
+        if exit_status.code().is_none() {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = exit_status.signal() {
+                eprintln!("QEMU process was terminated by signal {}", signal);
+            }
+        }
+    }
+    let qemu_exit_code = exit_status.code().ok_or(RunError::NoQemuExitCode)?;
+    Ok(match config.test_success_exit_code {
+        Some(code) if qemu_exit_code == code => 0,
+        Some(_) if qemu_exit_code == 0 => 1,
+        _ => qemu_exit_code,
+    })
+}
+
+/// State needed to shut down a test that timed out
+struct TimeoutSession<'a> {
+    qmp_socket: Option<&'a Path>,
+    screenshot: Option<&'a Path>,
+    diagnostic_files: Option<&'a DiagnosticFiles>,
+    symbolizer: Option<&'a Mutex<Symbolizer>>,
+}
+
+impl TimeoutSession<'_> {
+    /// Collects a screenshot and the registers through QMP and asks QEMU to
+    /// quit. Falls back to killing QEMU if that fails.
+    ///
+    /// Returns the path to the screenshot if one was taken.
+    async fn shutdown(&self, child: &mut Child) -> Result<Option<PathBuf>, RunError> {
+        let mut screenshot = None;
+        if let Some(socket) = self.qmp_socket {
+            match QmpClient::connect(socket).await {
+                Ok(mut qmp) => {
+                    if let Ok(png) = self.inspect(&mut qmp).await {
+                        screenshot = png;
+                        if qmp.quit().await.is_ok()
+                            && tokio::time::timeout(QUIT_TIMEOUT, child.wait())
+                                .await
+                                .is_ok()
+                        {
+                            return Ok(screenshot);
                         }
                     }
                 }
-                let qemu_exit_code = exit_status.code().ok_or(RunError::NoQemuExitCode)?;
-                match config.test_success_exit_code {
-                    Some(code) if qemu_exit_code == code => 0,
-                    Some(_) if qemu_exit_code == 0 => 1,
-                    _ => qemu_exit_code,
-                }
+                Err(e) => warn!("Failed to connect to QMP socket: {}", e),
             }
         }
-    } else {
-        let status = command.status().map_err(|error| RunError::Io {
-            context: IoErrorContext::QemuRunCommand {
-                command: format!("{:?}", command),
-            },
-            error,
-        })?;
-        status.code().unwrap_or(1)
-    };
+        child
+            .kill()
+            .await
+            .map_err(context(IoErrorContext::KillQemu))?;
+        Ok(screenshot)
+    }
 
-    Ok(exit_code)
+    async fn inspect(&self, qmp: &mut QmpClient) -> Result<Option<PathBuf>, qmp::QmpError> {
+        qmp.stop().await?;
+
+        let mut screenshot = None;
+        if let Some(png) = self.screenshot {
+            let ppm = png.with_extension("ppm");
+            match qmp.screendump(&ppm).await {
+                Ok(()) => screenshot = screenshot::convert(&ppm, png),
+                Err(e) => warn!("Failed to take screenshot: {}", e),
+            }
+        }
+
+        if self.diagnostic_files.is_some() {
+            match qmp.register_dump().await {
+                Ok(dump) => diagnostics::report_timeout(&dump, self.symbolizer),
+                Err(e) => warn!("Failed to dump registers through QMP: {}", e),
+            }
+        }
+        Ok(screenshot)
+    }
+}
+
+/// Loads the symbol files if backtrace symbolization or diagnostics are enabled
+fn create_symbolizer(config: &Config, symbol_files: &[&Path]) -> Option<Arc<Mutex<Symbolizer>>> {
+    if !config.symbolize_backtraces && !config.diagnostics {
+        return None;
+    }
+    match Symbolizer::new(symbol_files.iter().copied(), &config.backtrace_regex) {
+        Ok(symbolizer) if symbolizer.is_empty() => None,
+        Ok(symbolizer) => Some(Arc::new(Mutex::new(symbolizer))),
+        Err(e) => {
+            warn!("Disabling backtrace symbolization: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Forwards the piped serial output of QEMU to stdout in a separate task
+fn forward_serial(
+    child: &mut Child,
+    symbolizer: Option<Arc<Mutex<Symbolizer>>>,
+) -> Option<JoinHandle<()>> {
+    let (stdout, symbolizer) = (child.stdout.take()?, symbolizer?);
+    Some(tokio::spawn(async move {
+        if let Err(e) =
+            symbolize::forward(&symbolizer, BufReader::new(stdout), std::io::stdout()).await
+        {
+            warn!("Failed to forward serial output: {}", e);
+        }
+    }))
+}
+
+async fn stop_poller(poller: Option<JoinHandle<()>>) {
+    if let Some(poller) = poller {
+        poller.abort();
+        let _ = poller.await;
+    }
+}
+
+async fn join_forwarder(forwarder: Option<JoinHandle<()>>) {
+    if let Some(forwarder) = forwarder {
+        let _ = forwarder.await;
+    }
 }
 
 /// Running the disk image failed.
 #[derive(Debug, Error)]
 pub enum RunError {
     /// Test timed out
-    #[error("Test timed out")]
-    TestTimedOut,
+    #[error("Test timed out{}", screenshot_note(.screenshot))]
+    TestTimedOut {
+        /// Screenshot of the emulator display before QEMU was stopped
+        screenshot: Option<PathBuf>,
+    },
+
+    /// Test exited with a failure
+    #[error("Test failed with exit code {exit_code}{}", screenshot_note(.screenshot))]
+    TestFailed {
+        /// The mapped exit code of the test
+        exit_code: i32,
+        /// The last screenshot of the emulator display before QEMU exited
+        screenshot: Option<PathBuf>,
+    },
+
+    /// A placeholder in the run command could not be substituted
+    #[error("Invalid run command: {0}")]
+    Placeholder(#[from] PlaceholderError),
+
+    /// The QMP socket can't be created
+    #[error("{0}")]
+    Qmp(#[from] crate::qmp::QmpError),
 
     /// Failed to read QEMU exit code
     #[error("Failed to read QEMU exit code")]
@@ -129,10 +434,6 @@ pub enum IoErrorContext {
         command: String,
     },
 
-    /// Waiting for test with timeout failed
-    #[error("Failed to wait with timeout")]
-    WaitWithTimeout,
-
     /// Failed to kill QEMU
     #[error("Failed to kill QEMU")]
     KillQemu,
@@ -142,7 +443,45 @@ pub enum IoErrorContext {
     WaitForQemu,
 }
 
+fn screenshot_note(screenshot: &Option<PathBuf>) -> String {
+    match screenshot {
+        Some(path) => format!(" (screenshot: {})", path.display()),
+        None => String::new(),
+    }
+}
+
 /// Helper function for IO error construction
 fn context(context: IoErrorContext) -> impl FnOnce(io::Error) -> RunError {
     |error| RunError::Io { context, error }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_no_reboot_to_tests() {
+        let dir = std::env::temp_dir().join(format!("glue_gun-run-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("Cargo.toml");
+        std::fs::write(&manifest, "[package]\nname = \"kernel\"\n").unwrap();
+        let read = |overrides: &[&str]| {
+            let options = crate::config::ConfigOptions {
+                profile: None,
+                overrides: overrides.iter().map(|arg| arg.to_string()).collect(),
+            };
+            crate::config::read_config(&manifest, &options).unwrap()
+        };
+        let no_reboot = |command: Vec<String>| command.iter().any(|arg| arg == "-no-reboot");
+
+        let config = read(&[]);
+        assert!(no_reboot(run_command(&config, true, false)));
+        assert!(!no_reboot(run_command(&config, false, false)));
+        let config = read(&["test-no-reboot=false"]);
+        assert!(!no_reboot(run_command(&config, true, false)));
+        assert!(!no_reboot(run_command(&config, true, true)));
+        let config = read(&["test-no-reboot=false", "test-args=[\"-no-reboot\"]"]);
+        assert!(no_reboot(run_command(&config, true, false)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,172 @@
+//! Captures the emulator display of a test run as PNG
+//!
+//! QEMU's `screendump` writes binary PPM (P6) files. While a test runs the
+//! display is dumped periodically through QMP so the last frame is still
+//! available after QEMU exited with a failure.
+
+use crate::qmp::QmpClient;
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+/// How long the poller waits for QEMU to create the QMP socket
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Screenshot paths for a test run, all placed next to the disk image
+#[derive(Debug, Clone)]
+pub struct Screenshots {
+    /// The most recent periodic screendump
+    pub latest: PathBuf,
+    /// The PNG written when the test failed
+    pub failure: PathBuf,
+    /// The PNG written when the test timed out
+    pub timeout: PathBuf,
+}
+
+impl Screenshots {
+    pub fn new(image_path: &Path) -> Self {
+        let stem = image_path.file_stem().unwrap().to_str().unwrap();
+        Self {
+            latest: image_path.with_file_name(format!("{}-screen.ppm", stem)),
+            failure: image_path.with_file_name(format!("{}-failure.png", stem)),
+            timeout: image_path.with_file_name(format!("{}-timeout.png", stem)),
+        }
+    }
+
+    /// Removes screenshots of a previous run
+    pub fn reset(&self) {
+        for path in [&self.latest, &self.failure, &self.timeout] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    /// Dumps the display every `interval` until the task is aborted
+    ///
+    /// Every dump is written to a temporary file first, so `latest` always
+    /// holds a complete image.
+    pub fn poll(&self, socket: PathBuf, interval: Duration) -> JoinHandle<()> {
+        let latest = self.latest.clone();
+        let tmp = latest.with_extension("ppm.tmp");
+        tokio::spawn(async move {
+            let mut qmp = match QmpClient::connect_with_retry(&socket, CONNECT_TIMEOUT).await {
+                Ok(qmp) => qmp,
+                Err(e) => {
+                    warn!("Screenshots disabled, failed to connect to QMP: {}", e);
+                    return;
+                }
+            };
+            loop {
+                if let Err(e) = qmp.screendump(&tmp).await {
+                    debug!("Periodic screendump failed: {}", e);
+                    return;
+                }
+                let _ = std::fs::rename(&tmp, &latest);
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    /// Converts the most recent periodic screendump into the failure PNG
+    pub fn save_failure(&self) -> Option<PathBuf> {
+        if !self.latest.is_file() {
+            return None;
+        }
+        convert(&self.latest, &self.failure)
+    }
+}
+
+/// Converts `ppm` into `png` and logs the result
+pub fn convert(ppm: &Path, png: &Path) -> Option<PathBuf> {
+    match ppm_to_png(ppm, png) {
+        Ok(()) => {
+            let _ = std::fs::remove_file(ppm);
+            info!("Saved screenshot to {}", png.display());
+            Some(png.to_path_buf())
+        }
+        Err(e) => {
+            warn!("Failed to convert screenshot {}: {:#}", ppm.display(), e);
+            None
+        }
+    }
+}
+
+/// Converts a binary PPM (P6) file as written by QEMU into a PNG file
+pub fn ppm_to_png(ppm: &Path, png: &Path) -> Result<()> {
+    let data = std::fs::read(ppm).with_context(|| format!("Failed to read {}", ppm.display()))?;
+    let (width, height, pixels) = parse_ppm(&data)?;
+
+    let file = File::create(png).with_context(|| format!("Failed to create {}", png.display()))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(pixels))
+        .context("Failed to encode PNG")?;
+    Ok(())
+}
+
+/// Parses a P6 PPM with a maximum value of 255 into width, height and RGB data
+fn parse_ppm(data: &[u8]) -> Result<(u32, u32, &[u8])> {
+    let mut fields = Vec::with_capacity(4);
+    let mut pos = 0;
+    while fields.len() < 4 {
+        // Skip whitespace and comments between header fields
+        while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+            if data[pos] == b'#' {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+            } else {
+                pos += 1;
+            }
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            bail!("Truncated PPM header");
+        }
+        fields.push(std::str::from_utf8(&data[start..pos])?);
+    }
+    // Exactly one whitespace byte separates the header from the pixels
+    pos += 1;
+
+    if fields[0] != "P6" {
+        bail!("Unsupported PPM format {}", fields[0]);
+    }
+    let width: u32 = fields[1].parse().context("Invalid PPM width")?;
+    let height: u32 = fields[2].parse().context("Invalid PPM height")?;
+    if fields[3] != "255" {
+        bail!("Unsupported PPM maximum value {}", fields[3]);
+    }
+
+    let len = width as usize * height as usize * 3;
+    let pixels = data
+        .get(pos..pos + len)
+        .ok_or_else(|| anyhow!("PPM pixel data is truncated"))?;
+    Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ppm() {
+        let mut data = b"P6\n# QEMU\n2 1\n255\n".to_vec();
+        data.extend_from_slice(&[255, 0, 0, 0, 0, 255]);
+        let (width, height, pixels) = parse_ppm(&data).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(pixels, &[255, 0, 0, 0, 0, 255]);
+
+        assert!(parse_ppm(b"P6\n2 2\n255\n\x00\x00").is_err());
+    }
+}
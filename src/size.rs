@@ -0,0 +1,340 @@
+//! Size report and size budgets of a build
+//!
+//! Lists the allocated sections of the kernel and of the bootloader
+//! executable, the largest symbols of the kernel and how much each crate
+//! contributes, judged by the first segment of the demangled symbol paths.
+//! The numbers of the previous build of the same kernel are kept in its
+//! staging directory to show what changed.
+
+use anyhow::{Context, Result};
+use log::*;
+use object::{Object, ObjectSection, ObjectSymbol, SectionFlags, SymbolKind};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::build::BuildMetadata;
+
+/// The number of symbols and crates printed
+const TOP: usize = 20;
+
+/// Name of the file in the staging directory the last report is kept in
+const PREVIOUS: &str = "size.json";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeReport {
+    /// Allocated sections of the kernel
+    pub sections: BTreeMap<String, u64>,
+    /// Allocated sections of the bootloader executable, the kernel included
+    pub bootloader_sections: BTreeMap<String, u64>,
+    /// Demangled function and data symbols of the kernel, largest first
+    pub symbols: Vec<(String, u64)>,
+    /// The sum of the symbol sizes of each crate
+    pub crates: BTreeMap<String, u64>,
+    /// The kernel executable, the bootloader executable and the ISO
+    pub files: BTreeMap<String, u64>,
+}
+
+impl SizeReport {
+    /// Collects the sections and symbols of the kernel ELF
+    pub fn new(kernel: &[u8]) -> object::Result<Self> {
+        let file = object::File::parse(kernel)?;
+        let mut report = Self {
+            sections: sections(&file)?,
+            ..Self::default()
+        };
+        for symbol in file.symbols() {
+            if symbol.size() == 0 || !matches!(symbol.kind(), SymbolKind::Text | SymbolKind::Data) {
+                continue;
+            }
+            let name = addr2line::demangle_auto(Cow::Borrowed(symbol.name()?), None).into_owned();
+            *report
+                .crates
+                .entry(crate_of(&name).to_string())
+                .or_default() += symbol.size();
+            report.symbols.push((name, symbol.size()));
+        }
+        report
+            .symbols
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(report)
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        let object = |map: &BTreeMap<String, u64>| {
+            let mut object = json::JsonValue::new_object();
+            for (name, size) in map {
+                object[name.as_str()] = (*size).into();
+            }
+            object
+        };
+        json::object! {
+            sections: object(&self.sections),
+            bootloader_sections: object(&self.bootloader_sections),
+            crates: object(&self.crates),
+            files: object(&self.files),
+        }
+    }
+
+    /// Reads a report written by [`SizeReport::to_json`], without symbols
+    fn from_json(value: &json::JsonValue) -> Self {
+        let map = |value: &json::JsonValue| {
+            value
+                .entries()
+                .filter_map(|(name, size)| Some((name.to_string(), size.as_u64()?)))
+                .collect()
+        };
+        Self {
+            sections: map(&value["sections"]),
+            bootloader_sections: map(&value["bootloader_sections"]),
+            symbols: Vec::new(),
+            crates: map(&value["crates"]),
+            files: map(&value["files"]),
+        }
+    }
+
+    /// Renders the report, with the changes since `previous` if given
+    pub fn render(&self, previous: Option<&SizeReport>) -> String {
+        let mut out = String::new();
+        let table = |out: &mut String,
+                     title: &str,
+                     entries: &BTreeMap<String, u64>,
+                     previous: Option<&BTreeMap<String, u64>>| {
+            if entries.is_empty() {
+                return;
+            }
+            let mut entries: Vec<_> = entries.iter().collect();
+            entries.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+            out.push_str(title);
+            out.push('\n');
+            for (name, size) in entries.into_iter().take(TOP) {
+                let delta = previous
+                    .map(|previous| *size as i64 - previous.get(name).copied().unwrap_or(0) as i64);
+                out.push_str(&format!(
+                    "  {:>10}  {:>11}  {}\n",
+                    format_size(*size),
+                    delta.map(format_delta).unwrap_or_default(),
+                    name
+                ));
+            }
+        };
+        table(
+            &mut out,
+            "Files",
+            &self.files,
+            previous.map(|previous| &previous.files),
+        );
+        table(
+            &mut out,
+            "Kernel sections",
+            &self.sections,
+            previous.map(|previous| &previous.sections),
+        );
+        table(
+            &mut out,
+            "Bootloader sections",
+            &self.bootloader_sections,
+            previous.map(|previous| &previous.bootloader_sections),
+        );
+        table(
+            &mut out,
+            "Crates",
+            &self.crates,
+            previous.map(|previous| &previous.crates),
+        );
+        out.push_str("Largest symbols\n");
+        for (name, size) in self.symbols.iter().take(TOP) {
+            out.push_str(&format!("  {:>10}  {}\n", format_size(*size), name));
+        }
+        out
+    }
+
+    /// Returns a message for every entry of `budget` that is exceeded
+    ///
+    /// The keys of the budget are `iso`, and `kernel.<section>` and
+    /// `bootloader.<section>` with section names without the leading dot.
+    /// Keys matching nothing are ignored with a warning.
+    pub fn over_budget(&self, budget: &BTreeMap<String, u64>) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (key, limit) in budget {
+            let (what, size) = match key.split_once('.') {
+                None if key == "iso" => ("ISO".to_string(), self.files.get("iso")),
+                Some((binary @ ("kernel" | "bootloader"), section)) => {
+                    let sections = match binary {
+                        "kernel" => &self.sections,
+                        _ => &self.bootloader_sections,
+                    };
+                    let section = format!(".{}", section);
+                    let size = sections.get(&section);
+                    (format!("Section {} of the {}", section, binary), size)
+                }
+                _ => {
+                    warn!("Ignoring unknown `size-budget.{}`", key);
+                    continue;
+                }
+            };
+            match size {
+                Some(size) if size > limit => problems.push(format!(
+                    "{} is {}, {} over its budget of {}",
+                    what,
+                    format_size(*size),
+                    format_size(size - limit),
+                    format_size(*limit)
+                )),
+                Some(_) => {}
+                None => warn!("`size-budget.{}` matches no section", key),
+            }
+        }
+        problems
+    }
+}
+
+/// Returns the sizes of the allocated sections of an executable
+fn sections(file: &object::File) -> object::Result<BTreeMap<String, u64>> {
+    let mut sections = BTreeMap::new();
+    for section in file.sections() {
+        let is_alloc = match section.flags() {
+            SectionFlags::Elf { sh_flags } => sh_flags & u64::from(object::elf::SHF_ALLOC) != 0,
+            _ => true,
+        };
+        if is_alloc && section.size() > 0 {
+            *sections.entry(section.name()?.to_string()).or_default() += section.size();
+        }
+    }
+    Ok(sections)
+}
+
+/// Returns the crate a demangled symbol belongs to
+///
+/// Trait implementations are attributed to the crate of the type, symbols
+/// without a Rust path to `[other]`.
+fn crate_of(symbol: &str) -> &str {
+    let path = symbol.trim_start_matches('<');
+    let path = path.strip_prefix("impl ").unwrap_or(path);
+    match path.find("::") {
+        Some(end) if path[..end].chars().all(|c| c.is_alphanumeric() || c == '_') => &path[..end],
+        _ => "[other]",
+    }
+}
+
+/// Formats a size for reports and log messages
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn format_delta(delta: i64) -> String {
+    match delta {
+        0 => String::new(),
+        delta if delta > 0 => format!("+{}", format_size(delta as u64)),
+        delta => format!("-{}", format_size(delta.unsigned_abs())),
+    }
+}
+
+/// Creates the size report of a build and compares it to the previous one
+///
+/// The report is printed if `print` or `size-report` is set. Returns the
+/// problems if the build exceeds `size-budget`.
+pub fn glue_gun_size(
+    artifacts: &BuildMetadata,
+    bootloader_exe: Option<&Path>,
+    print: bool,
+) -> Result<Vec<String>> {
+    let kernel = std::fs::read(&artifacts.kernel_elf)
+        .with_context(|| format!("Failed to read {}", artifacts.kernel_elf.display()))?;
+    let mut report = SizeReport::new(&kernel)
+        .with_context(|| format!("Failed to parse {}", artifacts.kernel_elf.display()))?;
+    if let Some(path) = bootloader_exe {
+        let bootloader =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        report.bootloader_sections = object::File::parse(&*bootloader)
+            .and_then(|file| sections(&file))
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+    }
+    let files: [(&str, Option<PathBuf>); 3] = [
+        ("kernel", Some(artifacts.kernel_elf.clone())),
+        ("bootloader", bootloader_exe.map(Path::to_path_buf)),
+        ("iso", Some(artifacts.iso_img.clone())),
+    ];
+    for (name, path) in files {
+        if let Some(size) = path.and_then(|path| std::fs::metadata(path).ok()) {
+            report.files.insert(name.to_string(), size.len());
+        }
+    }
+
+    let path = artifacts.staging_dir.join(PREVIOUS);
+    let previous = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|text| json::parse(&text).ok())
+        .map(|value| SizeReport::from_json(&value));
+    if print || artifacts.config.size_report {
+        print!("{}", report.render(previous.as_ref()));
+    }
+    std::fs::write(&path, report.to_json().pretty(2))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(report.over_budget(&artifacts.config.size_budget))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_sizes() {
+        assert_eq!(crate_of("core::fmt::write"), "core");
+        assert_eq!(
+            crate_of("<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop"),
+            "alloc"
+        );
+        assert_eq!(crate_of("memcpy"), "[other]");
+
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let mut report = SizeReport::new(&exe).unwrap();
+        assert!(report.sections[".text"] > 0);
+        assert!(report.crates["glue_gun"] > 0);
+        assert!(report.symbols.windows(2).all(|w| w[0].1 >= w[1].1));
+        report.files.insert("iso".into(), 4 << 20);
+        report.bootloader_sections = BTreeMap::from([(".text".to_string(), 64 << 10)]);
+
+        let previous = SizeReport::from_json(&json::parse(&report.to_json().dump()).unwrap());
+        assert_eq!(previous.sections, report.sections);
+        assert_eq!(previous.bootloader_sections, report.bootloader_sections);
+        let mut smaller = previous.clone();
+        *smaller.sections.get_mut(".text").unwrap() -= 2048;
+        let rendered = report.render(Some(&smaller));
+        assert!(rendered.contains("+2.0 KiB"), "{}", rendered);
+
+        assert!(rendered.contains("Bootloader sections"), "{}", rendered);
+
+        let budget = BTreeMap::from([
+            ("kernel.text".to_string(), 1024),
+            ("bootloader.text".to_string(), 32 << 10),
+            ("bootloader.rodata".to_string(), 1024),
+            ("iso".to_string(), 8 << 20),
+        ]);
+        let problems = report.over_budget(&budget);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(
+            problems[0].starts_with("Section .text of the bootloader is 64.0 KiB"),
+            "{}",
+            problems[0]
+        );
+        assert!(
+            problems[1].starts_with("Section .text of the kernel is"),
+            "{}",
+            problems[1]
+        );
+    }
+}
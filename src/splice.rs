@@ -0,0 +1,98 @@
+//! Splices the kernel into a placeholder section of a prebuilt bootloader
+//!
+//! Used by `bootloader-glue = "section"`. The bootloader is built once with
+//! a section large enough for any kernel, for example
+//!
+//! ```ignore
+//! #[link_section = ".kernel"]
+//! static KERNEL: [u8; 16 << 20] = [0; 16 << 20];
+//! ```
+//!
+//! and glue_gun copies the kernel ELF to the start of that section of a copy
+//! of the bootloader, the rest of the section is zeroed. The bootloader finds
+//! the size of the kernel in its ELF header. This is what
+//! `llvm-objcopy --update-section` does for sections inside a segment, without
+//! recompiling the bootloader per kernel.
+
+use object::{Object, ObjectSection};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SpliceError {
+    #[error("failed to parse the bootloader executable: {0}")]
+    Parse(#[from] object::Error),
+
+    #[error("the bootloader has no section `{0}` to splice the kernel into")]
+    MissingSection(String),
+
+    #[error("section `{0}` of the bootloader has no data in the file, it must not be in .bss")]
+    NoFileData(String),
+
+    #[error(
+        "the kernel ({kernel} bytes) doesn't fit into section `{section}` of the bootloader \
+         ({size} bytes), enlarge the placeholder"
+    )]
+    TooSmall {
+        section: String,
+        size: u64,
+        kernel: usize,
+    },
+}
+
+/// Returns a copy of `bootloader` with `kernel` written into `section`
+pub fn splice(bootloader: &[u8], section: &str, kernel: &[u8]) -> Result<Vec<u8>, SpliceError> {
+    let file = object::File::parse(bootloader)?;
+    let placeholder = file
+        .section_by_name(section)
+        .ok_or_else(|| SpliceError::MissingSection(section.into()))?;
+    let (offset, size) = placeholder
+        .file_range()
+        .ok_or_else(|| SpliceError::NoFileData(section.into()))?;
+    if kernel.len() as u64 > size {
+        return Err(SpliceError::TooSmall {
+            section: section.into(),
+            size,
+            kernel: kernel.len(),
+        });
+    }
+
+    let mut spliced = bootloader.to_vec();
+    let start = offset as usize;
+    let data = &mut spliced[start..start + size as usize];
+    data[..kernel.len()].copy_from_slice(kernel);
+    data[kernel.len()..].fill(0);
+    Ok(spliced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splices_into_section() {
+        // rustc always emits a `.comment` section with its version
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let kernel = b"\x7fELF-kernel";
+
+        let spliced = splice(&exe, ".comment", kernel).unwrap();
+        assert_eq!(spliced.len(), exe.len());
+        let file = object::File::parse(&*spliced).unwrap();
+        let data = file.section_by_name(".comment").unwrap().data().unwrap();
+        assert_eq!(&data[..kernel.len()], kernel);
+        assert!(data[kernel.len()..].iter().all(|b| *b == 0));
+
+        let huge = vec![0xaa; exe.len()];
+        assert!(matches!(
+            splice(&exe, ".comment", &huge),
+            Err(SpliceError::TooSmall { .. })
+        ));
+        assert!(matches!(
+            splice(&exe, ".kernel", kernel),
+            Err(SpliceError::MissingSection(_))
+        ));
+        assert!(matches!(
+            splice(&exe, ".bss", kernel),
+            Err(SpliceError::NoFileData(_))
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+//! Isolates glue_gun builds that share a cargo target directory
+//!
+//! cargo runs test executables in parallel, each through its own glue_gun
+//! process, and `glue_gun watch` may rebuild while a manual `run` is in
+//! progress. Builds are serialized through an advisory lock on the target
+//! directory, write their intermediate files into a staging directory per
+//! kernel executable, and publish the ISO with an atomic rename so a running
+//! emulator never sees a half written image.
+
+use fs2::FileExt;
+use log::*;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// An exclusive lock on the glue_gun directory of a target directory
+///
+/// Released when dropped.
+#[derive(Debug)]
+pub struct BuildLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl BuildLock {
+    /// Waits until no other glue_gun process builds in `target_dir`
+    pub fn acquire(target_dir: &Path) -> io::Result<Self> {
+        let (file, path) = open_lock_file(target_dir)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                info!("Waiting for another glue_gun build to finish");
+                file.lock_exclusive()?;
+            }
+            Err(e) => return Err(e),
+        }
+        debug!("Acquired build lock {}", path.display());
+        Ok(Self { file, path })
+    }
+
+    /// Takes the lock if no other process holds it
+    pub fn try_acquire(target_dir: &Path) -> io::Result<Option<Self>> {
+        let (file, path) = open_lock_file(target_dir)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(Self { file, path })),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+        debug!("Released build lock {}", self.path.display());
+    }
+}
+
+fn open_lock_file(target_dir: &Path) -> io::Result<(File, PathBuf)> {
+    let dir = target_dir.join("glue_gun");
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(".lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    Ok((file, path))
+}
+
+/// Returns the staging directory of `kernel_exe`, `glue_gun/<kernel>-<hash>`
+///
+/// The hash of the absolute path keeps executables with the same name, for
+/// example from different profiles, apart.
+pub fn staging_dir(target_dir: &Path, kernel_exe: &Path) -> PathBuf {
+    let path = kernel_exe
+        .canonicalize()
+        .unwrap_or_else(|_| kernel_exe.to_path_buf());
+    let name = kernel_exe
+        .file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "kernel".into());
+    target_dir.join("glue_gun").join(format!(
+        "{}-{:016x}",
+        name,
+        fnv1a(path.to_string_lossy().as_bytes())
+    ))
+}
+
+/// Removes the staging directories of the executables of `crate_name`, of
+/// every executable if it is `None`
+///
+/// Waits for running builds. Returns the number of removed directories.
+pub fn remove_staging_dirs(target_dir: &Path, crate_name: Option<&str>) -> io::Result<usize> {
+    let dir = target_dir.join("glue_gun");
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let _lock = BuildLock::acquire(target_dir)?;
+    let mut removed = 0;
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(stem) = staging_stem(&name) else {
+            continue;
+        };
+        if entry.file_type()?.is_dir()
+            && crate_name.map_or(true, |krate| is_executable_of(stem, krate))
+        {
+            std::fs::remove_dir_all(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Returns the executable name of a staging directory name, `<kernel>-<hash>`
+fn staging_stem(name: &str) -> Option<&str> {
+    let (stem, hash) = name.rsplit_once('-')?;
+    (hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit())).then_some(stem)
+}
+
+/// Whether cargo names an executable of `crate_name` `stem`, test harnesses
+/// in `deps` get a `-<hash>` suffix and underscores instead of dashes
+fn is_executable_of(stem: &str, crate_name: &str) -> bool {
+    let harness = crate_name.replace('-', "_");
+    stem == crate_name
+        || stem == harness
+        || stem
+            .strip_prefix(&harness)
+            .is_some_and(|suffix| suffix.starts_with('-'))
+}
+
+/// FNV-1a, unlike `DefaultHasher` stable across Rust versions
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Returns a temporary path next to `dst` to write a file before publishing it
+pub fn temp_path(dst: &Path) -> PathBuf {
+    let name = dst
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dst.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+/// Atomically replaces `dst` with `tmp`, which has to be on the same file system
+pub fn publish(tmp: &Path, dst: &Path) -> io::Result<()> {
+    std::fs::rename(tmp, dst).map_err(|e| {
+        let _ = std::fs::remove_file(tmp);
+        e
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isolates_builds() {
+        let dir = std::env::temp_dir().join(format!("glue_gun-staging-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let lock = BuildLock::acquire(&dir).unwrap();
+        assert!(BuildLock::try_acquire(&dir).unwrap().is_none());
+        drop(lock);
+        assert!(BuildLock::try_acquire(&dir).unwrap().is_some());
+
+        let debug = staging_dir(&dir, Path::new("/target/debug/kernel"));
+        let release = staging_dir(&dir, Path::new("/target/release/kernel"));
+        assert_ne!(debug, release);
+        assert_eq!(debug, staging_dir(&dir, Path::new("/target/debug/kernel")));
+        assert!(debug
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("kernel-"));
+
+        std::fs::create_dir_all(&debug).unwrap();
+        let test = staging_dir(&dir, Path::new("/target/debug/deps/my_kernel-0123abcd"));
+        std::fs::create_dir_all(&test).unwrap();
+        std::fs::create_dir_all(dir.join("glue_gun/symbols")).unwrap();
+        assert_eq!(remove_staging_dirs(&dir, Some("my-kernel")).unwrap(), 1);
+        assert!(!test.exists() && debug.exists());
+        assert_eq!(remove_staging_dirs(&dir, None).unwrap(), 1);
+        assert!(!debug.exists() && dir.join("glue_gun/symbols").exists());
+
+        let iso = dir.join("kernel.iso");
+        let tmp = temp_path(&iso);
+        std::fs::write(&tmp, "image").unwrap();
+        publish(&tmp, &iso).unwrap();
+        assert!(!tmp.exists());
+        assert_eq!(std::fs::read_to_string(&iso).unwrap(), "image");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
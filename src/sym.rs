@@ -1,6 +1,13 @@
 use log::*;
 
-use std::{path::Path, process};
+use object::Endian;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{config::Compression, size::format_size};
 
 pub fn create_bochs_symfile<'a, I>(symfiles: I, out_path: &Path)
 where
@@ -32,7 +39,316 @@ where
     );
 }
 
-pub fn create_sym_file(in_path: &Path, out_path: &Path, strip_in: bool) {
+/// Writes the debug information of `in_path` to `out_path`, optionally
+/// stripping it from `in_path` afterwards
+///
+/// The stripped executable gets a `.gnu_debuglink` to `out_path`, so
+/// debuggers find the symbols next to it. The debug sections of `out_path`
+/// are compressed with `compression`. Done in-process, `llvm-objcopy` from
+/// the `llvm-tools-preview` component is only needed for executables the
+/// ELF rewriter can't handle.
+pub fn create_sym_file(in_path: &Path, out_path: &Path, strip_in: bool, compression: Compression) {
+    match split_debug(in_path, out_path, strip_in, compression) {
+        Ok(()) => {
+            info!(
+                "Created symbol file: {}",
+                out_path.file_name().unwrap().to_str().unwrap()
+            );
+            if strip_in {
+                debug!("Stripped symbols from {}", in_path.display());
+            }
+        }
+        Err(e) => {
+            warn!(
+                "Failed to split debug info of {}: {}, falling back to llvm-objcopy",
+                in_path.display(),
+                e
+            );
+            objcopy_sym_file(in_path, out_path, strip_in, compression);
+        }
+    }
+}
+
+fn split_debug(
+    in_path: &Path,
+    out_path: &Path,
+    strip_in: bool,
+    compression: Compression,
+) -> anyhow::Result<()> {
+    let data = std::fs::read(in_path)?;
+    let mut debug = only_keep_debug(&data)?;
+    if compression != Compression::None {
+        let size = debug.len();
+        debug = compress_debug(&debug, compression)?;
+        info!(
+            "Compressed debug sections of {} with {}: {} -> {}",
+            out_path.file_name().unwrap().to_str().unwrap(),
+            compression,
+            format_size(size as u64),
+            format_size(debug.len() as u64)
+        );
+    }
+    std::fs::write(out_path, debug)?;
+    if strip_in {
+        let name = out_path.file_name().unwrap().to_string_lossy();
+        let crc = crc32fast::hash(&std::fs::read(out_path)?);
+        replace(in_path, &strip_debug(&data, Some((&name, crc)))?, in_path)?;
+    }
+    Ok(())
+}
+
+/// Whether `llvm-objcopy --strip-debug` removes a section of this name
+fn is_debug_section(name: &[u8]) -> bool {
+    name.starts_with(b".debug") || name.starts_with(b".zdebug") || name == b".gdb_index"
+}
+
+/// Returns the debug file of an ELF executable, like `objcopy --only-keep-debug`
+///
+/// Allocated sections except notes keep their headers but lose their
+/// contents, so addresses in the debug information still resolve.
+/// Non-allocated sections, the debug sections and the symbol table, are kept.
+pub fn only_keep_debug(data: &[u8]) -> object::build::Result<Vec<u8>> {
+    use object::{build::elf::SectionData, elf};
+    let mut builder = object::build::elf::Builder::read(data)?;
+    for section in &mut builder.sections {
+        if section.is_alloc() && section.sh_type != elf::SHT_NOTE {
+            section.sh_type = elf::SHT_NOBITS;
+            section.data = SectionData::UninitializedData(section.sh_size);
+        }
+    }
+    // The dynamic symbols and versions lost their sections
+    for symbol in &mut builder.dynamic_symbols {
+        symbol.delete = true;
+    }
+    for version in &mut builder.versions {
+        version.delete = true;
+    }
+    for file in &mut builder.version_files {
+        file.delete = true;
+    }
+    builder.version_base = None;
+    // Segments only cover the notes that kept their contents
+    let notes: Vec<(u64, u64)> = builder
+        .sections
+        .iter()
+        .filter(|section| section.is_alloc() && section.sh_type == elf::SHT_NOTE)
+        .map(|section| (section.sh_offset, section.sh_offset + section.sh_size))
+        .collect();
+    for segment in &mut builder.segments {
+        let start = segment.p_offset;
+        let end = segment.p_offset + segment.p_filesz;
+        segment.p_filesz = notes
+            .iter()
+            .filter(|(note_start, _)| (start..end).contains(note_start))
+            .map(|(_, note_end)| note_end.min(&end) - start)
+            .max()
+            .unwrap_or(0);
+    }
+    write(builder)
+}
+
+/// Returns the executable without debug information, like `objcopy --strip-debug`
+///
+/// With `debuglink`, the file name and CRC-32 of the symbol file, a
+/// `.gnu_debuglink` section is added or replaced like `--add-gnu-debuglink`
+/// does.
+pub fn strip_debug(data: &[u8], debuglink: Option<(&str, u32)>) -> object::build::Result<Vec<u8>> {
+    let mut builder = object::build::elf::Builder::read(data)?;
+    let mut deleted = Vec::new();
+    for section in &mut builder.sections {
+        if is_debug_section(&section.name) {
+            section.delete = true;
+            deleted.push(section.id());
+        }
+    }
+    // Relocations of the debug sections, only present with `--emit-relocs`
+    for section in &mut builder.sections {
+        if section
+            .sh_info_section
+            .is_some_and(|target| deleted.contains(&target))
+        {
+            section.delete = true;
+        }
+    }
+    if let Some((name, crc)) = debuglink {
+        // The file name, padded to 4 bytes, followed by the CRC
+        let mut link = name.as_bytes().to_vec();
+        link.resize((link.len() + 4) & !3, 0);
+        link.extend(builder.endian.write_u32_bytes(crc));
+        let section = match builder
+            .sections
+            .iter_mut()
+            .find(|section| &*section.name == b".gnu_debuglink")
+        {
+            Some(section) => section,
+            None => {
+                let section = builder.sections.add();
+                section.name = b".gnu_debuglink"[..].into();
+                section.sh_type = object::elf::SHT_PROGBITS;
+                section.sh_addralign = 4;
+                section
+            }
+        };
+        section.data = object::build::elf::SectionData::Data(link.into());
+    }
+    write(builder)
+}
+
+/// Writes a copy of `in_path` without debug info to `out_path`, linked to `sym_path`
+///
+/// Logs the size before and after.
+pub fn strip_copy(in_path: &Path, out_path: &Path, sym_path: &Path) -> anyhow::Result<()> {
+    let data = std::fs::read(in_path)?;
+    let name = sym_path.file_name().unwrap().to_string_lossy();
+    let crc = crc32fast::hash(&std::fs::read(sym_path)?);
+    let stripped = strip_debug(&data, Some((&name, crc)))?;
+    std::fs::write(out_path, &stripped)?;
+    std::fs::set_permissions(out_path, std::fs::metadata(in_path)?.permissions())?;
+    info!(
+        "Stripped debug info of {}: {} -> {}",
+        in_path.file_name().unwrap().to_str().unwrap(),
+        format_size(data.len() as u64),
+        format_size(stripped.len() as u64)
+    );
+    Ok(())
+}
+
+/// Compresses the debug sections like `objcopy --compress-debug-sections`
+///
+/// Each section gets a compression header and the `SHF_COMPRESSED` flag,
+/// gdb, lldb and the symbolizer decompress it when loading. Sections that
+/// don't get smaller are left alone.
+pub fn compress_debug(data: &[u8], compression: Compression) -> anyhow::Result<Vec<u8>> {
+    use anyhow::Context;
+    use object::{build::elf::SectionData, elf};
+    let ch_type = match compression {
+        Compression::None => return Ok(data.to_vec()),
+        Compression::Zlib => elf::ELFCOMPRESS_ZLIB,
+        Compression::Zstd => elf::ELFCOMPRESS_ZSTD,
+    };
+    let mut builder = object::build::elf::Builder::read(data)?;
+    let (endian, is_64) = (builder.endian, builder.is_64);
+    for section in &mut builder.sections {
+        if !section.name.starts_with(b".debug")
+            || section.sh_flags & u64::from(elf::SHF_COMPRESSED) != 0
+        {
+            continue;
+        }
+        let SectionData::Data(contents) = &section.data else {
+            continue;
+        };
+        let compressed =
+            compress(contents, compression).context("Failed to compress debug sections")?;
+        let mut out = Vec::with_capacity(24 + compressed.len());
+        out.extend(endian.write_u32_bytes(ch_type));
+        if is_64 {
+            out.extend(endian.write_u32_bytes(0));
+            out.extend(endian.write_u64_bytes(contents.len() as u64));
+            out.extend(endian.write_u64_bytes(section.sh_addralign));
+        } else {
+            out.extend(endian.write_u32_bytes(contents.len() as u32));
+            out.extend(endian.write_u32_bytes(section.sh_addralign as u32));
+        }
+        out.extend(compressed);
+        if out.len() >= contents.len() {
+            continue;
+        }
+        section.sh_flags |= u64::from(elf::SHF_COMPRESSED);
+        section.sh_addralign = if is_64 { 8 } else { 4 };
+        section.data = SectionData::Data(out.into());
+    }
+    Ok(write(builder)?)
+}
+
+/// Compresses `data` as a whole
+pub fn compress(data: &[u8], compression: Compression) -> anyhow::Result<Vec<u8>> {
+    use anyhow::Context;
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zlib => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        Compression::Zstd => {
+            zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL).context("zstd failed")
+        }
+    }
+}
+
+/// Returns the GNU build-id of an executable
+pub fn build_id(data: &[u8]) -> object::Result<Option<Vec<u8>>> {
+    use object::Object;
+    Ok(object::File::parse(data)?.build_id()?.map(<[u8]>::to_vec))
+}
+
+/// Adds a `.note.gnu.build-id` section with the SHA-1 of the executable
+///
+/// Unlike the note the linker creates with `--build-id` it isn't loaded, which
+/// is enough for debuggers and the symbol store. Returns the new executable
+/// and its build-id.
+pub fn add_build_id(data: &[u8]) -> object::build::Result<(Vec<u8>, Vec<u8>)> {
+    use object::elf;
+    let id = sha1_smol::Sha1::from(data).digest().bytes().to_vec();
+    let mut builder = object::build::elf::Builder::read(data)?;
+    let word = |value: u32| builder.endian.write_u32_bytes(value);
+    let mut note = Vec::new();
+    note.extend(word(elf::ELF_NOTE_GNU.len() as u32 + 1));
+    note.extend(word(id.len() as u32));
+    note.extend(word(elf::NT_GNU_BUILD_ID));
+    // "GNU" with its terminator fills the 4 byte alignment
+    note.extend(elf::ELF_NOTE_GNU);
+    note.push(0);
+    note.extend(&id);
+
+    let section = builder.sections.add();
+    section.name = b".note.gnu.build-id"[..].into();
+    section.sh_type = elf::SHT_NOTE;
+    section.sh_addralign = 4;
+    section.data = object::build::elf::SectionData::Note(note.into());
+    Ok((write(builder)?, id))
+}
+
+/// Returns the build-id of the executable at `path`, with the path of an
+/// executable that has it
+///
+/// An executable without build-id is left alone, a copy with one is written
+/// to `out_path`, which may be `path` if the executable is glue_gun's own.
+pub fn ensure_build_id(path: &Path, out_path: &Path) -> anyhow::Result<(PathBuf, Vec<u8>)> {
+    let data = std::fs::read(path)?;
+    if let Some(id) = build_id(&data)? {
+        return Ok((path.to_path_buf(), id));
+    }
+    info!(
+        "{} has no build-id, adding one to {} (link with `--build-id` to avoid this)",
+        path.display(),
+        out_path.display()
+    );
+    let (data, id) = add_build_id(&data)?;
+    if let Some(dir) = out_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    replace(out_path, &data, path)?;
+    Ok((out_path.to_path_buf(), id))
+}
+
+/// Atomically replaces the executable at `path`, with the permissions of `like`
+fn replace(path: &Path, data: &[u8], like: &Path) -> std::io::Result<()> {
+    let permissions = std::fs::metadata(like)?.permissions();
+    let tmp = crate::staging::temp_path(path);
+    std::fs::write(&tmp, data)?;
+    std::fs::set_permissions(&tmp, permissions)?;
+    crate::staging::publish(&tmp, path)
+}
+
+fn write(builder: object::build::elf::Builder<'_>) -> object::build::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    builder.write(&mut out)?;
+    Ok(out)
+}
+
+fn objcopy_sym_file(in_path: &Path, out_path: &Path, strip_in: bool, compression: Compression) {
     use std::process::Command;
     // get access to llvm tools shipped in the llvm-tools-preview rustup component
     let llvm_tools = match llvm_tools::LlvmTools::new() {
@@ -56,6 +372,9 @@ pub fn create_sym_file(in_path: &Path, out_path: &Path, strip_in: bool) {
     // Create separate symbol file
     let mut cmd = Command::new(&objcopy);
     cmd.arg("--only-keep-debug");
+    if compression != Compression::None {
+        cmd.arg(format!("--compress-debug-sections={}", compression));
+    }
     cmd.arg(in_path);
     cmd.arg(out_path);
     debug!("Executing:\n {:#?}", cmd);
@@ -75,6 +394,7 @@ pub fn create_sym_file(in_path: &Path, out_path: &Path, strip_in: bool) {
         // Strip symbols inplace from in_path
         let mut cmd = Command::new(&objcopy);
         cmd.arg("--strip-debug");
+        cmd.arg(format!("--add-gnu-debuglink={}", out_path.display()));
         cmd.arg(in_path);
         cmd.arg(in_path);
         debug!("Executing: {:#?}", cmd);
@@ -88,3 +408,199 @@ pub fn create_sym_file(in_path: &Path, out_path: &Path, strip_in: bool) {
         debug!("Stripped symbols from {}", in_path.display());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{Object, ObjectSection, SectionKind};
+
+    /// Name, type, flags, address and size of every section
+    ///
+    /// The symbol table is left out and string tables are compared without
+    /// their size, both tools lay them out differently.
+    fn section_table(data: &[u8]) -> Vec<(String, u32, u64, u64, u64)> {
+        use object::{
+            elf,
+            read::elf::{ElfFile64, SectionHeader},
+        };
+
+        let file = ElfFile64::<object::Endianness>::parse(data).unwrap();
+        file.sections()
+            .filter(|section| !matches!(section.name(), Ok(".symtab" | ".strtab" | ".shstrtab")))
+            .map(|section| {
+                let header = section.elf_section_header();
+                let sh_type = header.sh_type(file.endian());
+                (
+                    section.name().unwrap().to_string(),
+                    sh_type,
+                    header.sh_flags(file.endian()),
+                    section.address(),
+                    if sh_type == elf::SHT_STRTAB {
+                        0
+                    } else {
+                        section.size()
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Returns llvm-objcopy of the `llvm-tools-preview` component, or the one on the `PATH`
+    fn find_objcopy() -> Option<PathBuf> {
+        let from_path = || {
+            let objcopy = PathBuf::from("llvm-objcopy");
+            std::process::Command::new(&objcopy)
+                .arg("--version")
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|_| objcopy)
+        };
+        match llvm_tools::LlvmTools::new() {
+            Ok(tools) => tools
+                .tool(&llvm_tools::exe("llvm-objcopy"))
+                .or_else(from_path),
+            Err(_) => from_path(),
+        }
+    }
+
+    fn objcopy(objcopy: &Path, flag: &str, input: &Path, output: &Path) -> Vec<u8> {
+        let status = std::process::Command::new(objcopy)
+            .arg(flag)
+            .arg(input)
+            .arg(output)
+            .status()
+            .unwrap();
+        assert!(status.success(), "llvm-objcopy {} failed", flag);
+        std::fs::read(output).unwrap()
+    }
+
+    #[test]
+    fn splits_debug_info() {
+        // The unit tests are built with debug info
+        let exe = std::env::current_exe().unwrap();
+        let data = std::fs::read(&exe).unwrap();
+
+        let debug = only_keep_debug(&data).unwrap();
+        let file = object::File::parse(&*debug).unwrap();
+        assert!(!file
+            .section_by_name(".debug_info")
+            .unwrap()
+            .data()
+            .unwrap()
+            .is_empty());
+        let text = file.section_by_name(".text").unwrap();
+        assert_eq!(text.kind(), SectionKind::UninitializedData);
+        assert!(debug.len() < data.len());
+
+        let stripped = strip_debug(&data, None).unwrap();
+        let file = object::File::parse(&*stripped).unwrap();
+        assert!(file.section_by_name(".debug_info").is_none());
+        assert!(!file
+            .section_by_name(".text")
+            .unwrap()
+            .data()
+            .unwrap()
+            .is_empty());
+        assert!(file.symbols().count() > 0);
+    }
+
+    #[test]
+    fn splits_like_llvm_objcopy() {
+        let Some(tool) = find_objcopy() else {
+            eprintln!("llvm-objcopy not found, skipping the comparison");
+            eprintln!("  Install it through: `rustup component add llvm-tools-preview`");
+            return;
+        };
+        let exe = std::env::current_exe().unwrap();
+        let data = std::fs::read(&exe).unwrap();
+        let out = std::env::temp_dir().join(format!("glue_gun-objcopy-{}", std::process::id()));
+        let expected = objcopy(&tool, "--only-keep-debug", &exe, &out);
+        assert_eq!(
+            section_table(&only_keep_debug(&data).unwrap()),
+            section_table(&expected)
+        );
+        let expected = objcopy(&tool, "--strip-debug", &exe, &out);
+        assert_eq!(
+            section_table(&strip_debug(&data, None).unwrap()),
+            section_table(&expected)
+        );
+        let _ = std::fs::remove_file(&out);
+    }
+
+    #[test]
+    fn compresses_debug_sections() {
+        let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        // Compressing all debug info of the unoptimized test is slow
+        let mut builder = object::build::elf::Builder::read(&*data).unwrap();
+        for section in &mut builder.sections {
+            section.delete = is_debug_section(&section.name) && &*section.name != b".debug_abbrev";
+        }
+        let debug = only_keep_debug(&write(builder).unwrap()).unwrap();
+        let file = object::File::parse(&*debug).unwrap();
+        let expected = file
+            .section_by_name(".debug_abbrev")
+            .unwrap()
+            .data()
+            .unwrap();
+
+        for compression in [Compression::Zlib, Compression::Zstd] {
+            let compressed = compress_debug(&debug, compression).unwrap();
+            assert!(compressed.len() < debug.len());
+            let file = object::File::parse(&*compressed).unwrap();
+            let section = file.section_by_name(".debug_abbrev").unwrap();
+            assert!(section.size() < expected.len() as u64);
+            let data = section.uncompressed_data().unwrap();
+            assert_eq!(&*data, expected, "{}", compression);
+        }
+    }
+
+    #[test]
+    fn links_symbols() {
+        let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+
+        let stripped = strip_debug(&data, Some(("test.sym", 0x1234_5678))).unwrap();
+        let file = object::File::parse(&*stripped).unwrap();
+        assert_eq!(
+            file.gnu_debuglink().unwrap(),
+            Some((&b"test.sym"[..], 0x1234_5678))
+        );
+
+        // Without the note of the linker
+        let mut builder = object::build::elf::Builder::read(&*stripped).unwrap();
+        for section in &mut builder.sections {
+            if &*section.name == b".note.gnu.build-id" {
+                section.sh_type = object::elf::SHT_PROGBITS;
+            }
+        }
+        let data = write(builder).unwrap();
+        assert_eq!(build_id(&data).unwrap(), None);
+        let (with_id, id) = add_build_id(&data).unwrap();
+        assert_eq!(id.len(), 20);
+        assert_eq!(build_id(&with_id).unwrap(), Some(id.clone()));
+        // Carried over into the symbol file
+        let debug = only_keep_debug(&with_id).unwrap();
+        assert_eq!(build_id(&debug).unwrap(), Some(id.clone()));
+
+        // The executable itself isn't changed
+        let dir = std::env::temp_dir().join(format!("glue_gun-build-id-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let kernel = dir.join("kernel");
+        std::fs::write(&kernel, &data).unwrap();
+        let staged = dir.join("staging/kernel");
+        assert_eq!(
+            ensure_build_id(&kernel, &staged).unwrap(),
+            (staged.clone(), id.clone())
+        );
+        assert_eq!(std::fs::read(&kernel).unwrap(), data);
+        assert_eq!(
+            build_id(&std::fs::read(&staged).unwrap()).unwrap(),
+            Some(id)
+        );
+        let (path, _) = ensure_build_id(&staged, &dir.join("unused")).unwrap();
+        assert_eq!(path, staged);
+        assert!(!dir.join("unused").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
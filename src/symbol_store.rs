@@ -0,0 +1,101 @@
+//! Archives symbol files by GNU build-id
+//!
+//! The store has the layout gdb and lldb expect from a debug file directory,
+//! `.build-id/<first byte>/<remaining bytes>.debug` with the build-id in hex,
+//! so old crash logs can be symbolized after the next build replaced
+//! `kernel.sym` and `bootloader.sym`:
+//!
+//! ```text
+//! (gdb) set debug-file-directory <store>
+//! (lldb) settings append target.debug-file-search-paths <store>
+//! ```
+
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::Config, Manifest};
+
+/// Returns the store configured with `symbol-store`
+///
+/// Defaults to `<target-dir>/glue_gun/symbols` of the kernel.
+pub fn location(config: &Config, kernel: &Manifest) -> PathBuf {
+    match &config.symbol_store {
+        Some(path) => kernel.crate_path.join(path),
+        None => kernel.target_dir.join("glue_gun").join("symbols"),
+    }
+}
+
+/// Formats a build-id the way `readelf -n` and the store do
+pub fn to_hex(build_id: &[u8]) -> String {
+    build_id
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Parses a build-id given in hex
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() < 4 || hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!(
+            "`{}` is not a build-id, expected an even number of hex digits",
+            hex
+        ));
+    }
+    Ok((0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+/// Returns the path of the symbol file of `build_id` in `store`
+pub fn path(store: &Path, build_id: &[u8]) -> PathBuf {
+    let hex = to_hex(build_id);
+    store
+        .join(".build-id")
+        .join(&hex[..2])
+        .join(format!("{}.debug", &hex[2..]))
+}
+
+/// Copies `sym` into `store` unless a symbol file of `build_id` is archived
+pub fn archive(store: &Path, build_id: &[u8], sym: &Path) -> io::Result<PathBuf> {
+    let dst = path(store, build_id);
+    if dst.is_file() {
+        debug!("Symbols of build-id {} already archived", to_hex(build_id));
+        return Ok(dst);
+    }
+    std::fs::create_dir_all(dst.parent().unwrap())?;
+    let tmp = crate::staging::temp_path(&dst);
+    std::fs::copy(sym, &tmp)?;
+    crate::staging::publish(&tmp, &dst)?;
+    debug!("Archived {} as {}", sym.display(), dst.display());
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archives_by_build_id() {
+        let dir = std::env::temp_dir().join(format!("glue_gun-symbols-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let id = parse_hex("0a1B2c3d").unwrap();
+        assert_eq!(id, [0x0a, 0x1b, 0x2c, 0x3d]);
+        assert_eq!(to_hex(&id), "0a1b2c3d");
+        assert!(parse_hex("0a1").is_err());
+        assert!(parse_hex("0x1b2c").is_err());
+
+        let sym = dir.join("kernel.sym");
+        std::fs::write(&sym, "symbols").unwrap();
+        let archived = archive(&dir, &id, &sym).unwrap();
+        assert_eq!(archived, dir.join(".build-id/0a/1b2c3d.debug"));
+        assert_eq!(std::fs::read_to_string(&archived).unwrap(), "symbols");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
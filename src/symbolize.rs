@@ -0,0 +1,292 @@
+//! Resolves addresses printed by the kernel over serial against the symbol files
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use regex::Regex;
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+
+/// Default regex for finding addresses in the serial output.
+///
+/// Matches `RIP=<hex>` as printed in register dumps and every `0x` prefixed
+/// hex number with at least 6 digits, which covers most backtrace formats.
+pub const DEFAULT_BACKTRACE_REGEX: &str =
+    r"(?i)(?:\brip\s*[=:]\s*(?:0x)?|\b0x)(?P<addr>[0-9a-f]{6,16})\b";
+
+/// A single resolved stack frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub addr: u64,
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl Frame {
+    /// Describes the frame without its address
+    pub fn location(&self) -> String {
+        let mut out = format!("in {}", self.function.as_deref().unwrap_or("??"));
+        if let Some(file) = &self.file {
+            out += &format!(" at {}", file);
+            if let Some(line) = self.line {
+                out += &format!(":{}", line);
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x} {}", self.addr, self.location())
+    }
+}
+
+/// Resolves addresses against a list of symbol files
+///
+/// Symbol files are queried in the order they were given, the first one
+/// that knows the address wins.
+pub struct Symbolizer {
+    loaders: Vec<(PathBuf, addr2line::Loader)>,
+    regex: Regex,
+}
+
+impl Symbolizer {
+    /// Loads the given symbol files. Missing files are skipped with a warning.
+    pub fn new<'a, I>(symfiles: I, regex: &str) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        let regex = Regex::new(regex).context("Invalid backtrace regex")?;
+        let mut loaders = Vec::new();
+        for path in symfiles {
+            if !path.is_file() {
+                warn!("Symbol file {} does not exist", path.display());
+                continue;
+            }
+            let loader = addr2line::Loader::new(path)
+                .map_err(|e| anyhow!("Failed to load symbol file {}: {}", path.display(), e))?;
+            loaders.push((path.to_path_buf(), loader));
+        }
+        Ok(Self { loaders, regex })
+    }
+
+    /// Returns true if no symbol file could be loaded
+    pub fn is_empty(&self) -> bool {
+        self.loaders.is_empty()
+    }
+
+    /// Extracts all addresses matched by the backtrace regex from `line`
+    ///
+    /// Uses the capture group named `addr` or, if the regex has none, the
+    /// first capture group.
+    pub fn addresses(&self, line: &str) -> Vec<u64> {
+        self.regex
+            .captures_iter(line)
+            .filter_map(|caps| caps.name("addr").or_else(|| caps.get(1)))
+            .filter_map(|m| {
+                let hex = m.as_str();
+                let hex = hex
+                    .strip_prefix("0x")
+                    .or_else(|| hex.strip_prefix("0X"))
+                    .unwrap_or(hex);
+                u64::from_str_radix(hex, 16).ok()
+            })
+            .collect()
+    }
+
+    /// Resolves a single address including inlined frames
+    ///
+    /// Returns an empty vector if no symbol file contains the address.
+    pub fn resolve(&self, addr: u64) -> Vec<Frame> {
+        for (path, loader) in &self.loaders {
+            let mut frames = Vec::new();
+            match loader.find_frames(addr) {
+                Ok(mut iter) => {
+                    while let Ok(Some(frame)) = iter.next() {
+                        let function = frame
+                            .function
+                            .as_ref()
+                            .and_then(|f| f.demangle().ok())
+                            .map(|f| f.into_owned());
+                        let (file, line) = match frame.location {
+                            Some(loc) => (loc.file.map(str::to_owned), loc.line),
+                            None => (None, None),
+                        };
+                        frames.push(Frame {
+                            addr,
+                            function,
+                            file,
+                            line,
+                        });
+                    }
+                }
+                Err(e) => debug!("Failed to resolve {:#x} in {}: {}", addr, path.display(), e),
+            }
+
+            if frames.is_empty() {
+                if let Some(symbol) = loader.find_symbol(addr) {
+                    frames.push(Frame {
+                        addr,
+                        function: Some(addr2line::demangle_auto(symbol.into(), None).into_owned()),
+                        file: None,
+                        line: None,
+                    });
+                }
+            }
+            if !frames.is_empty() {
+                return frames;
+            }
+        }
+        Vec::new()
+    }
+
+    /// Resolves all addresses found in `line`
+    pub fn symbolize_line(&self, line: &str) -> Vec<Frame> {
+        self.addresses(line)
+            .into_iter()
+            .flat_map(|addr| self.resolve(addr))
+            .collect()
+    }
+}
+
+/// Copies `input` to `output` line by line and prints the symbolized
+/// frames right after every line containing known addresses.
+///
+/// The symbolizer is only locked while a line is resolved.
+pub async fn forward<R: AsyncBufRead + Unpin, W: Write>(
+    symbolizer: &Mutex<Symbolizer>,
+    mut input: R,
+    mut output: W,
+) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if input.read_until(b'\n', &mut buf).await? == 0 {
+            return Ok(());
+        }
+        output.write_all(&buf)?;
+
+        let line = String::from_utf8_lossy(&buf);
+        let frames = symbolizer.lock().unwrap().symbolize_line(&line);
+        for (i, frame) in frames.iter().enumerate() {
+            writeln!(output, "    #{:<2} {}", i, frame)?;
+        }
+        output.flush()?;
+    }
+}
+
+/// Symbolizes `log`, or stdin, with the archived symbol files of `build_ids`
+///
+/// Implements `glue_gun symbolize`.
+pub async fn glue_gun_symbolize(
+    store: &Path,
+    build_ids: &[String],
+    log: Option<&Path>,
+    regex: &str,
+) -> Result<()> {
+    let mut symfiles = Vec::new();
+    for build_id in build_ids {
+        let path = crate::symbol_store::path(store, &crate::symbol_store::parse_hex(build_id)?);
+        if !path.is_file() {
+            bail!("No symbols of build-id {} in {}", build_id, store.display());
+        }
+        symfiles.push(path);
+    }
+    let symbolizer = Mutex::new(Symbolizer::new(
+        symfiles.iter().map(PathBuf::as_path),
+        regex,
+    )?);
+    match log {
+        Some(path) => {
+            let file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            forward(&symbolizer, BufReader::new(file), std::io::stdout()).await?;
+        }
+        None => {
+            forward(
+                &symbolizer,
+                BufReader::new(tokio::io::stdin()),
+                std::io::stdout(),
+            )
+            .await?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_addresses() {
+        let sym = Symbolizer::new([], DEFAULT_BACKTRACE_REGEX).unwrap();
+        assert_eq!(
+            sym.addresses("RIP=000000000010f3e2 RFL=00000046"),
+            vec![0x10f3e2]
+        );
+        assert_eq!(
+            sym.addresses("backtrace: 0xffff800000012345 0x0000000000201000 0x10"),
+            vec![0xffff800000012345, 0x201000]
+        );
+        assert!(sym
+            .addresses("panicked at 'oops', src/main.rs:12:5")
+            .is_empty());
+    }
+
+    #[inline(never)]
+    fn resolve_me() -> u32 {
+        std::hint::black_box(42)
+    }
+
+    #[test]
+    fn resolves_addresses() {
+        use object::{Object, ObjectSymbol};
+        assert_eq!(resolve_me(), 42);
+
+        // The test executable has debug info, resolve one of its own functions
+        let exe = std::env::current_exe().unwrap();
+        let data = std::fs::read(&exe).unwrap();
+        let file = object::File::parse(&*data).unwrap();
+        let addr = file
+            .symbols()
+            .find(|symbol| {
+                symbol.name().is_ok_and(|name| {
+                    addr2line::demangle_auto(name.into(), None)
+                        .contains("symbolize::tests::resolve_me")
+                })
+            })
+            .expect("resolve_me is not in the symbol table")
+            .address();
+
+        let sym = Symbolizer::new([exe.as_path()], DEFAULT_BACKTRACE_REGEX).unwrap();
+        assert!(!sym.is_empty());
+        let frames = sym.resolve(addr + 1);
+        let frame = frames.last().expect("address not resolved");
+        assert_eq!(frame.addr, addr + 1);
+        assert!(
+            frame
+                .function
+                .as_deref()
+                .is_some_and(|function| function.ends_with("symbolize::tests::resolve_me")),
+            "{}",
+            frame
+        );
+        assert!(
+            frame
+                .file
+                .as_deref()
+                .is_some_and(|file| file.ends_with("symbolize.rs")),
+            "{}",
+            frame
+        );
+        assert!(frame.line.is_some(), "{}", frame);
+    }
+}
@@ -55,7 +55,7 @@ impl Watchlist {
 
 #[allow(clippy::await_holding_lock)]
 pub async fn glue_gun_watch(
-    kernel_exec_path: std::path::PathBuf,
+    kernel: crate::build::Artifact,
     manifests: Manifests,
     cli_options: CliOptions,
 ) {
@@ -65,17 +65,18 @@ pub async fn glue_gun_watch(
     let mut runtime = RuntimeConfig::default();
 
     let mut crates_to_watch: BTreeSet<PathBuf> = BTreeSet::new();
-    crates_to_watch.insert(manifests.bootloader.crate_path.clone());
     crates_to_watch.insert(manifests.kernel.crate_path.clone());
 
-    let mut boot_deps: BTreeSet<PathBuf> = manifests
-        .bootloader
-        .meta
-        .get_recurisve_local_dependencies()
-        .iter()
-        .map(|x| x.path.clone())
-        .collect();
-    crates_to_watch.append(&mut boot_deps);
+    if let Some(bootloader) = &manifests.bootloader {
+        crates_to_watch.insert(bootloader.crate_path.clone());
+        let mut boot_deps: BTreeSet<PathBuf> = bootloader
+            .meta
+            .get_recurisve_local_dependencies()
+            .iter()
+            .map(|x| x.path.clone())
+            .collect();
+        crates_to_watch.append(&mut boot_deps);
+    }
 
     let mut kernel_deps: BTreeSet<PathBuf> = manifests
         .kernel
@@ -130,8 +131,21 @@ pub async fn glue_gun_watch(
                 if ek.is_modify() || ek.is_remove() || ek.is_create() {
                     info!("file changed: {:?}", event);
 
-                    let _artifacts =
-                        crate::build::glue_gun_build(&kernel_exec_path, &manifests, &cli_options);
+                    // The configuration may be among the changed files
+                    match crate::config::read_config(
+                        &manifests.kernel.cargo_toml,
+                        &cli_options.config,
+                    ) {
+                        Ok(config) => {
+                            let _artifacts = crate::build::glue_gun_build(
+                                &kernel,
+                                &manifests,
+                                &config,
+                                &cli_options,
+                            );
+                        }
+                        Err(e) => error!("{:#}", e),
+                    }
 
                     return fut;
                 }
@@ -0,0 +1,15 @@
+use std::env;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg-bins=-T{}/linker.ld", dir);
+    println!("cargo:rerun-if-changed=linker.ld");
+    println!("cargo:rerun-if-env-changed=KERNEL");
+
+    if env::var_os("CARGO_FEATURE_BINARY").is_some() {
+        let kernel = env::var("KERNEL")
+            .expect("The `binary` feature needs the path of the kernel in the KERNEL environment variable");
+        println!("cargo:rerun-if-changed={}", kernel);
+        println!("cargo:rustc-env=KERNEL_PATH={}", kernel);
+    }
+}
@@ -0,0 +1,31 @@
+// Multiboot2 entry: GRUB jumps to `_start` in 32-bit protected mode with the
+// boot information address in ebx. Sets up a stack and calls `bootloader_main`.
+
+.section .multiboot2, "a"
+.align 8
+multiboot2_header:
+    .long 0xe85250d6
+    .long 0
+    .long multiboot2_header_end - multiboot2_header
+    .long -(0xe85250d6 + (multiboot2_header_end - multiboot2_header))
+    // End tag
+    .short 0
+    .short 0
+    .long 8
+multiboot2_header_end:
+
+.section .bss
+.align 16
+stack_bottom:
+    .skip 64 * 1024
+stack_top:
+
+.section .text
+.global _start
+_start:
+    mov esp, offset stack_top
+    push ebx
+    call bootloader_main
+1:
+    hlt
+    jmp 1b
@@ -0,0 +1,96 @@
+// Multiboot2 entry: GRUB jumps to `_start` in 32-bit protected mode with the
+// boot information address in ebx. Identity maps the first GiB, switches to
+// long mode and calls `bootloader_main`.
+
+.section .multiboot2, "a"
+.align 8
+multiboot2_header:
+    .long 0xe85250d6
+    .long 0
+    .long multiboot2_header_end - multiboot2_header
+    .long -(0xe85250d6 + (multiboot2_header_end - multiboot2_header))
+    // End tag
+    .short 0
+    .short 0
+    .long 8
+multiboot2_header_end:
+
+.section .bss
+.align 4096
+pml4:
+    .skip 4096
+pdpt:
+    .skip 4096
+pd:
+    .skip 4096
+stack_bottom:
+    .skip 64 * 1024
+stack_top:
+
+.section .rodata
+.align 8
+gdt64:
+    .quad 0
+    // Present, executable, 64-bit code segment
+    .quad (1 << 43) | (1 << 44) | (1 << 47) | (1 << 53)
+gdt64_pointer:
+    .short gdt64_pointer - gdt64 - 1
+    .quad gdt64
+
+.section .text
+.code32
+.global _start
+_start:
+    mov esp, offset stack_top
+    mov edi, ebx
+
+    // PML4[0] -> PDPT, PDPT[0] -> PD, PD maps 512 huge pages of 2 MiB
+    mov eax, offset pdpt
+    or eax, 0x3
+    mov [pml4], eax
+    mov eax, offset pd
+    or eax, 0x3
+    mov [pdpt], eax
+    xor ecx, ecx
+1:
+    mov eax, ecx
+    shl eax, 21
+    or eax, 0x83
+    mov [pd + ecx * 8], eax
+    inc ecx
+    cmp ecx, 512
+    jne 1b
+
+    // Enable PAE, load the page table, set EFER.LME and enable paging
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, offset pml4
+    mov cr3, eax
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, 1 << 8
+    wrmsr
+    mov eax, cr0
+    or eax, 1 << 31
+    mov cr0, eax
+
+    // Far return into the 64-bit code segment
+    lgdt [gdt64_pointer]
+    push 0x8
+    mov eax, offset long_mode_start
+    push eax
+    retf
+
+.code64
+long_mode_start:
+    xor eax, eax
+    mov ss, ax
+    mov ds, ax
+    mov es, ax
+    // Zero extend the boot information address
+    mov edi, edi
+    call bootloader_main
+2:
+    hlt
+    jmp 2b
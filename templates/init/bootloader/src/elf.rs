@@ -0,0 +1,70 @@
+//! Loads the segments of an ELF executable to their physical addresses
+
+use core::fmt;
+
+const PT_LOAD: u32 = 1;
+
+/// The embedded kernel is not a loadable ELF executable
+#[derive(Debug)]
+pub enum Error {
+    NotElf,
+    UnsupportedClass(u8),
+    Truncated,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotElf => f.write_str("not an ELF file"),
+            Error::UnsupportedClass(class) => write!(f, "unsupported ELF class {}", class),
+            Error::Truncated => f.write_str("truncated ELF file"),
+        }
+    }
+}
+
+fn read(elf: &[u8], offset: usize, size: usize) -> Result<u64, Error> {
+    let bytes = elf.get(offset..offset + size).ok_or(Error::Truncated)?;
+    let mut value = [0u8; 8];
+    value[..size].copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+/// Copies all `PT_LOAD` segments to their physical address, zeroes their
+/// `.bss` part and returns the entry point
+///
+/// # Safety
+///
+/// The segments must fit into identity mapped memory that is not used by the
+/// bootloader itself.
+pub unsafe fn load(elf: &[u8]) -> Result<usize, Error> {
+    if elf.get(0..4) != Some(b"\x7fELF") {
+        return Err(Error::NotElf);
+    }
+    // Offsets of e_entry, e_phoff, e_phentsize, e_phnum and of p_offset,
+    // p_paddr, p_filesz, p_memsz for ELF32 and ELF64
+    let (word, entry, phoff, phentsize, phnum, p_offset, p_paddr, p_filesz, p_memsz) =
+        match elf[4] {
+            1 => (4, 24, 28, 42, 44, 4, 12, 16, 20),
+            2 => (8, 24, 32, 54, 56, 8, 24, 32, 40),
+            class => return Err(Error::UnsupportedClass(class)),
+        };
+
+    let phoff = read(elf, phoff, word)? as usize;
+    let phentsize = read(elf, phentsize, 2)? as usize;
+    for index in 0..read(elf, phnum, 2)? as usize {
+        let header = phoff + index * phentsize;
+        if read(elf, header, 4)? as u32 != PT_LOAD {
+            continue;
+        }
+        let offset = read(elf, header + p_offset, word)? as usize;
+        let paddr = read(elf, header + p_paddr, word)? as usize;
+        let filesz = read(elf, header + p_filesz, word)? as usize;
+        let memsz = read(elf, header + p_memsz, word)? as usize;
+        let data = elf.get(offset..offset + filesz).ok_or(Error::Truncated)?;
+
+        let dst = paddr as *mut u8;
+        core::ptr::copy_nonoverlapping(data.as_ptr(), dst, filesz);
+        core::ptr::write_bytes(dst.add(filesz), 0, memsz.saturating_sub(filesz));
+    }
+    Ok(read(elf, entry, word)? as usize)
+}
@@ -0,0 +1,43 @@
+//! Loads the kernel and provides the few drivers both need
+
+#![no_std]
+
+pub mod elf;
+pub mod serial;
+
+use core::arch::asm;
+
+/// Writes a byte to an I/O port
+///
+/// # Safety
+///
+/// Writing to an I/O port can have arbitrary side effects on the hardware.
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack));
+}
+
+/// Reads a byte from an I/O port
+///
+/// # Safety
+///
+/// Reading from an I/O port can have side effects on the hardware.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack));
+    value
+}
+
+/// Halts the CPU until the next interrupt
+pub fn hlt() {
+    unsafe { asm!("hlt", options(nomem, nostack)) };
+}
+
+/// Exits QEMU through the `isa-debug-exit` device at port 0xf4
+///
+/// QEMU exits with `(code << 1) | 1`. Halts if the device is missing.
+pub fn exit_qemu(code: u8) -> ! {
+    unsafe { outb(0xf4, code) };
+    loop {
+        hlt();
+    }
+}
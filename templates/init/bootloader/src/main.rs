@@ -0,0 +1,29 @@
+#![no_std]
+#![no_main]
+
+use bootloader::serial::Serial;
+use core::arch::global_asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+global_asm!(include_str!("boot.s"));
+
+/// The kernel executable, embedded through the `KERNEL` environment variable
+static KERNEL: &[u8] = include_bytes!(env!("KERNEL_PATH"));
+
+/// Called by `boot.s` with the address of the multiboot2 boot information
+#[no_mangle]
+extern "C" fn bootloader_main(multiboot_info: usize) -> ! {
+    let entry = match unsafe { bootloader::elf::load(KERNEL) } {
+        Ok(entry) => entry,
+        Err(e) => panic!("Failed to load the kernel: {}", e),
+    };
+    let kernel: extern "C" fn(usize) -> ! = unsafe { core::mem::transmute(entry) };
+    kernel(multiboot_info)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let _ = writeln!(Serial, "bootloader: {}", info);
+    bootloader::exit_qemu(0x11)
+}
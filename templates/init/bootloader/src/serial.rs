@@ -0,0 +1,31 @@
+//! Polling driver for the first serial port, which QEMU connects to stdio
+
+use crate::{inb, outb};
+use core::fmt;
+
+const COM1: u16 = 0x3f8;
+
+/// The first serial port, usable through `write!`
+pub struct Serial;
+
+impl Serial {
+    fn write_byte(byte: u8) {
+        unsafe {
+            // Wait until the transmit holding register is empty
+            while inb(COM1 + 5) & 0x20 == 0 {}
+            outb(COM1, byte);
+        }
+    }
+}
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                Self::write_byte(b'\r');
+            }
+            Self::write_byte(byte);
+        }
+        Ok(())
+    }
+}
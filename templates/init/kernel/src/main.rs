@@ -0,0 +1,30 @@
+#![no_std]
+#![no_main]
+
+use bootloader::serial::Serial;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+/// The kernel entry point, called by the bootloader with the address of the
+/// multiboot2 boot information
+#[no_mangle]
+pub extern "C" fn _start(multiboot_info: usize) -> ! {
+    let _ = writeln!(
+        Serial,
+        "Hello from {{name}}! Boot information at {:#x}",
+        multiboot_info
+    );
+    halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let _ = writeln!(Serial, "{}", info);
+    bootloader::exit_qemu(0x11)
+}
+
+fn halt() -> ! {
+    loop {
+        bootloader::hlt();
+    }
+}
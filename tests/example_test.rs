@@ -29,6 +29,40 @@ fn print_help() {
     };
 }
 
+#[test]
+fn parse_display() {
+    let app = create_cli();
+    let matches = app
+        .try_get_matches_from(vec!["glue_gun", "run", "--display", "vnc"])
+        .unwrap();
+    let run = matches.subcommand_matches("run").unwrap();
+    assert_eq!(run.get_one::<String>("display").unwrap(), "vnc");
+
+    let app = create_cli();
+    assert!(app
+        .try_get_matches_from(vec!["glue_gun", "run", "--display", "x11"])
+        .is_err());
+}
+
+#[test]
+fn parse_cargo_args() {
+    let app = create_cli();
+    let matches = app
+        .try_get_matches_from(vec![
+            "glue_gun",
+            "build",
+            "--",
+            "--features",
+            "smp",
+            "-Zbuild-std=core",
+            "--locked",
+        ])
+        .unwrap();
+    let build = matches.subcommand_matches("build").unwrap();
+    let args: Vec<&String> = build.get_many::<String>("cargo_args").unwrap().collect();
+    assert_eq!(args, ["--features", "smp", "-Zbuild-std=core", "--locked"]);
+}
+
 #[test]
 fn test_submodule() {
     let res = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources");